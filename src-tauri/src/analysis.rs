@@ -0,0 +1,698 @@
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::experiment::data::MeasurementRecord;
+
+/// Points below this fraction of the peak power are left out of the linear
+/// fit, so the knee at threshold does not bend the line.
+const FIT_MIN_FRACTION: f64 = 0.2;
+/// Points above this fraction of the peak power are left out as well, so the
+/// start of thermal rollover does not flatten it.
+const FIT_MAX_FRACTION: f64 = 0.9;
+/// Fewest points needed for a fit or a derivative estimate.
+const MIN_POINTS: usize = 3;
+/// Points on either side of a candidate kink that the slopes before and after
+/// it are fitted to, so measurement noise does not show up as kinks.
+const KINK_WINDOW: usize = 4;
+
+/// Limits of the kink and rollover screening, from the `analysis` part of the
+/// `experiment` section of `lab_config.json`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct AnalysisSettings {
+    /// Largest difference between the slopes of the lines fitted to the
+    /// `KINK_WINDOW` (4) points ending at a point and to those starting at
+    /// it, as a fraction of the slope efficiency, that is not flagged as a
+    /// kink
+    pub kink_tolerance: f64,
+    /// Rollover begins where dL/dI falls below this fraction of its maximum
+    pub rollover_slope_fraction: f64,
+    /// Lowest current at which rollover may begin; `None` fails any rollover
+    /// within the sweep
+    pub min_rollover_current_ma: Option<f64>,
+    /// Lowest current at which the power may peak; `None` requires the power
+    /// to keep rising up to the end of the sweep
+    pub min_peak_power_current_ma: Option<f64>,
+    /// Without `min_peak_power_current_ma`, how far the power at the end of
+    /// the sweep may lie below the peak, as a fraction of the peak power, so
+    /// noise at the top of a rising curve does not fail it
+    pub peak_power_tolerance: f64,
+}
+
+impl Default for AnalysisSettings {
+    fn default() -> Self {
+        AnalysisSettings {
+            kink_tolerance: 0.2,
+            rollover_slope_fraction: 0.5,
+            min_rollover_current_ma: None,
+            min_peak_power_current_ma: None,
+            peak_power_tolerance: 0.02,
+        }
+    }
+}
+
+/// One point of an L-I curve.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct LiPoint {
+    #[serde(rename = "current_mA")]
+    pub current_ma: f64,
+    #[serde(rename = "power_mW")]
+    pub power_mw: f64,
+}
+
+/// Where the power of a curve was measured.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CurveSource {
+    PowerMeter,
+    MonitorPhotodiode,
+}
+
+/// Threshold current by each method, `None` where the curve does not allow
+/// the estimate (too few points, no lasing).
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct ThresholdEstimates {
+    /// Zero crossing of the line fitted to the lasing region
+    #[serde(rename = "linear_fit_mA")]
+    pub linear_fit_ma: Option<f64>,
+    /// Current at which dP/dI reaches half its maximum
+    #[serde(rename = "first_derivative_mA")]
+    pub first_derivative_ma: Option<f64>,
+    /// Current at the peak of d²P/dI²
+    #[serde(rename = "second_derivative_mA")]
+    pub second_derivative_ma: Option<f64>,
+}
+
+/// Deviation of one fitted point from the line.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct FitResidual {
+    #[serde(rename = "current_mA")]
+    pub current_ma: f64,
+    #[serde(rename = "residual_mW")]
+    pub residual_mw: f64,
+}
+
+/// Least-squares line through the lasing region, P = slope I + intercept.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct LinearFit {
+    #[serde(rename = "slope_W_per_A")]
+    pub slope_w_per_a: f64,
+    #[serde(rename = "intercept_mW")]
+    pub intercept_mw: f64,
+    pub r_squared: f64,
+    #[serde(rename = "rms_residual_mW")]
+    pub rms_residual_mw: f64,
+    pub residuals: Vec<FitResidual>,
+}
+
+/// What a point marked by the screening shows.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnnotationKind {
+    Kink,
+    RolloverOnset,
+    PeakPower,
+}
+
+/// Point of an L-I curve marked by the screening.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct Annotation {
+    pub kind: AnnotationKind,
+    #[serde(rename = "current_mA")]
+    pub current_ma: f64,
+    #[serde(rename = "power_mW")]
+    pub power_mw: f64,
+}
+
+/// Pass/fail flags of the kink and rollover checks of one curve.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Screening {
+    /// All checks passed
+    pub passed: bool,
+    /// No kink in the lasing region
+    pub kink_passed: bool,
+    /// No rollover, or rollover from `min_rollover_current_ma` on
+    pub rollover_passed: bool,
+    /// Power peaks from `min_peak_power_current_ma` on, or near the end
+    pub peak_power_passed: bool,
+    #[serde(rename = "rollover_onset_mA")]
+    pub rollover_onset_ma: Option<f64>,
+    /// Kinks, rollover onset and peak power, by current
+    pub annotations: Vec<Annotation>,
+}
+
+/// Results for one L-I curve of a sweep.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct CurveAnalysis {
+    pub source: CurveSource,
+    /// Power meter input, `None` for the monitor photodiode
+    pub module: Option<u8>,
+    pub port: Option<u8>,
+    /// Points with a valid power reading
+    pub points: usize,
+    pub threshold: ThresholdEstimates,
+    /// Slope of the linear fit
+    #[serde(rename = "slope_efficiency_W_per_A")]
+    pub slope_efficiency_w_per_a: Option<f64>,
+    #[serde(rename = "max_power_mW")]
+    pub max_power_mw: Option<f64>,
+    #[serde(rename = "current_at_max_power_mA")]
+    pub current_at_max_power_ma: Option<f64>,
+    pub fit: Option<LinearFit>,
+    /// `None` when the curve has no lasing region to screen
+    pub screening: Option<Screening>,
+}
+
+/// Analyzes every curve of a sweep: one per power meter channel, plus the
+/// monitor photodiode when its power was recorded.
+///
+/// Currents are the setpoints, or the measured current in power sweeps.
+/// Powers are converted from dBm to mW; out-of-range readings are skipped.
+pub fn analyze_sweep(records: &[MeasurementRecord], settings: &AnalysisSettings) -> Vec<CurveAnalysis> {
+    let mut channels: Vec<(Option<u8>, Option<u8>)> = Vec::new();
+    for record in records {
+        let channel = (record.module, record.port);
+        if !channels.contains(&channel) {
+            channels.push(channel);
+        }
+    }
+
+    let channel_records = |module: Option<u8>, port: Option<u8>| {
+        records.iter().filter(move |r| r.module == module && r.port == port)
+    };
+    let mut results: Vec<CurveAnalysis> = channels
+        .iter()
+        .filter(|(module, _)| module.is_some())
+        .map(|&(module, port)| {
+            let points = curve(channel_records(module, port), |r| r.power_dbm);
+            analyze_curve(CurveSource::PowerMeter, module, port, &points, settings)
+        })
+        .collect();
+    // The monitor photodiode is read once per step and repeated in the
+    // record of every channel, so one channel's records hold the curve
+    if let Some(&(module, port)) = channels.first() {
+        if channel_records(module, port).any(|r| r.monitor_power_dbm.is_some()) {
+            let points = curve(channel_records(module, port), |r| r.monitor_power_dbm);
+            results.push(analyze_curve(CurveSource::MonitorPhotodiode, None, None, &points, settings));
+        }
+    }
+    info!("Analyzed {} L-I curves", results.len());
+    results
+}
+
+/// L-I points of `records` sorted by current, with `power_dbm` in mW.
+fn curve<'a>(
+    records: impl Iterator<Item = &'a MeasurementRecord>,
+    power_dbm: impl Fn(&MeasurementRecord) -> Option<f64>,
+) -> Vec<LiPoint> {
+    let mut points: Vec<LiPoint> = records
+        .filter_map(|r| {
+            let dbm = power_dbm(r)?;
+            Some(LiPoint {
+                current_ma: r.current_ma.unwrap_or(r.measured_current_ma),
+                power_mw: 10f64.powf(dbm / 10.0),
+            })
+        })
+        .collect();
+    points.sort_by(|a, b| a.current_ma.total_cmp(&b.current_ma));
+    points
+}
+
+/// Analyzes one L-I curve; `points` must be sorted by current.
+pub fn analyze_curve(
+    source: CurveSource,
+    module: Option<u8>,
+    port: Option<u8>,
+    points: &[LiPoint],
+    settings: &AnalysisSettings,
+) -> CurveAnalysis {
+    let peak = points.iter().copied().max_by(|a, b| a.power_mw.total_cmp(&b.power_mw));
+    let fit = peak.and_then(|peak| fit_lasing_region(points, peak));
+    if fit.is_none() && !points.is_empty() {
+        warn!("No linear region found in the L-I curve of {:?} {:?}:{:?}", source, module, port);
+    }
+
+    let threshold = ThresholdEstimates {
+        linear_fit_ma: fit
+            .as_ref()
+            .filter(|fit| fit.slope_w_per_a > 0.0)
+            .map(|fit| -fit.intercept_mw / fit.slope_w_per_a),
+        first_derivative_ma: first_derivative_threshold(points),
+        second_derivative_ma: second_derivative_threshold(points),
+    };
+
+    let screening = peak
+        .zip(fit.as_ref())
+        .filter(|(_, fit)| fit.slope_w_per_a > 0.0)
+        .map(|(peak, fit)| screen(points, peak, fit.slope_w_per_a, settings));
+    if let Some(screening) = screening.as_ref().filter(|s| !s.passed) {
+        warn!(
+            "L-I curve of {:?} {:?}:{:?} failed screening (kinks: {}, rollover: {}, peak power: {})",
+            source,
+            module,
+            port,
+            if screening.kink_passed { "pass" } else { "fail" },
+            if screening.rollover_passed { "pass" } else { "fail" },
+            if screening.peak_power_passed { "pass" } else { "fail" },
+        );
+    }
+
+    CurveAnalysis {
+        source,
+        module,
+        port,
+        points: points.len(),
+        threshold,
+        slope_efficiency_w_per_a: fit.as_ref().map(|fit| fit.slope_w_per_a),
+        max_power_mw: peak.map(|p| p.power_mw),
+        current_at_max_power_ma: peak.map(|p| p.current_ma),
+        fit,
+        screening,
+    }
+}
+
+/// Runs the kink, rollover and peak power checks on a curve with a lasing
+/// region; `slope` is the slope efficiency.
+fn screen(points: &[LiPoint], peak: LiPoint, slope: f64, settings: &AnalysisSettings) -> Screening {
+    let rollover_onset_ma = rollover_onset(points, settings.rollover_slope_fraction);
+    // Kinks are looked for from the lasing region up to rollover, where dL/dI
+    // is expected to stay constant
+    let lasing_end = rollover_onset_ma.unwrap_or(peak.current_ma);
+    let lasing: Vec<LiPoint> = points
+        .iter()
+        .copied()
+        .skip_while(|p| p.power_mw < FIT_MIN_FRACTION * peak.power_mw)
+        .take_while(|p| p.current_ma <= lasing_end)
+        .collect();
+    let kinks = kinks(&lasing, slope, settings.kink_tolerance);
+
+    let kink_passed = kinks.is_empty();
+    let rollover_passed = match (rollover_onset_ma, settings.min_rollover_current_ma) {
+        (None, _) => true,
+        (Some(onset), Some(min)) => onset >= min,
+        (Some(_), None) => false,
+    };
+    let last = points.last().copied().unwrap_or(peak);
+    let peak_power_passed = match settings.min_peak_power_current_ma {
+        Some(min) => peak.current_ma >= min,
+        None => last.power_mw >= (1.0 - settings.peak_power_tolerance) * peak.power_mw,
+    };
+
+    let mut annotations: Vec<Annotation> = kinks
+        .iter()
+        .map(|p| Annotation {
+            kind: AnnotationKind::Kink,
+            current_ma: p.current_ma,
+            power_mw: p.power_mw,
+        })
+        .collect();
+    if let Some(onset) = rollover_onset_ma {
+        annotations.push(Annotation {
+            kind: AnnotationKind::RolloverOnset,
+            current_ma: onset,
+            power_mw: power_at(points, onset),
+        });
+    }
+    annotations.push(Annotation {
+        kind: AnnotationKind::PeakPower,
+        current_ma: peak.current_ma,
+        power_mw: peak.power_mw,
+    });
+    annotations.sort_by(|a, b| a.current_ma.total_cmp(&b.current_ma));
+
+    Screening {
+        passed: kink_passed && rollover_passed && peak_power_passed,
+        kink_passed,
+        rollover_passed,
+        peak_power_passed,
+        rollover_onset_ma,
+        annotations,
+    }
+}
+
+/// Points where dL/dI changes by more than `tolerance` times `slope`. The
+/// slopes before and after each point are fitted to `KINK_WINDOW` points;
+/// of neighbouring points flagged for the same kink, the strongest is kept.
+fn kinks(lasing: &[LiPoint], slope: f64, tolerance: f64) -> Vec<LiPoint> {
+    if lasing.len() < 2 * KINK_WINDOW - 1 {
+        return Vec::new();
+    }
+    let mut kinks = Vec::new();
+    // Strongest point of the current run of flagged points, with its change
+    let mut run: Option<(LiPoint, f64)> = None;
+    for i in KINK_WINDOW - 1..=lasing.len() - KINK_WINDOW {
+        let before = linear_fit(&lasing[i + 1 - KINK_WINDOW..=i]);
+        let after = linear_fit(&lasing[i..i + KINK_WINDOW]);
+        let change = match (before, after) {
+            (Some(before), Some(after)) => ((after.slope_w_per_a - before.slope_w_per_a) / slope).abs(),
+            _ => 0.0,
+        };
+        if change > tolerance {
+            if run.is_none_or(|(_, strongest)| change > strongest) {
+                run = Some((lasing[i], change));
+            }
+        } else if let Some((point, _)) = run.take() {
+            kinks.push(point);
+        }
+    }
+    kinks.extend(run.map(|(point, _)| point));
+    kinks
+}
+
+/// Current above the steepest part of the curve at which dP/dI first drops
+/// below `fraction` of its maximum, interpolated between samples. The slopes
+/// are fitted to `KINK_WINDOW` points, as for kinks, so a single noisy point
+/// does not read as rollover.
+fn rollover_onset(points: &[LiPoint], fraction: f64) -> Option<f64> {
+    let slope = windowed_slopes(points);
+    if slope.len() < MIN_POINTS {
+        return None;
+    }
+    let (steepest, &(_, max)) = slope
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.1.total_cmp(&b.1))?;
+    if max <= 0.0 {
+        return None;
+    }
+    let limit = max * fraction;
+    slope[steepest..].windows(2).find(|w| w[1].1 < limit).map(|w| {
+        let ((x0, d0), (x1, d1)) = (w[0], w[1]);
+        x0 + (d0 - limit) / (d0 - d1) * (x1 - x0)
+    })
+}
+
+/// dP/dI of the line fitted to each run of `KINK_WINDOW` points, at the mean
+/// current of the run.
+fn windowed_slopes(points: &[LiPoint]) -> Vec<(f64, f64)> {
+    points
+        .windows(KINK_WINDOW)
+        .filter_map(|window| {
+            let fit = linear_fit(window)?;
+            let current_ma = window.iter().map(|p| p.current_ma).sum::<f64>() / KINK_WINDOW as f64;
+            Some((current_ma, fit.slope_w_per_a))
+        })
+        .collect()
+}
+
+/// Power at `current_ma`, interpolated between the points around it.
+fn power_at(points: &[LiPoint], current_ma: f64) -> f64 {
+    points
+        .windows(2)
+        .find(|w| w[1].current_ma >= current_ma)
+        .map(|w| {
+            let (a, b) = (w[0], w[1]);
+            if b.current_ma == a.current_ma {
+                return a.power_mw;
+            }
+            a.power_mw + (current_ma - a.current_ma) / (b.current_ma - a.current_ma) * (b.power_mw - a.power_mw)
+        })
+        .unwrap_or_else(|| points.last().map_or(0.0, |p| p.power_mw))
+}
+
+/// Fits a line to the points between `FIT_MIN_FRACTION` and
+/// `FIT_MAX_FRACTION` of the peak power, up to the peak current.
+fn fit_lasing_region(points: &[LiPoint], peak: LiPoint) -> Option<LinearFit> {
+    if peak.power_mw <= 0.0 {
+        return None;
+    }
+    let region: Vec<LiPoint> = points
+        .iter()
+        .copied()
+        .filter(|p| p.current_ma <= peak.current_ma)
+        .filter(|p| (FIT_MIN_FRACTION..=FIT_MAX_FRACTION).contains(&(p.power_mw / peak.power_mw)))
+        .collect();
+    linear_fit(&region)
+}
+
+fn linear_fit(points: &[LiPoint]) -> Option<LinearFit> {
+    if points.len() < MIN_POINTS {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.current_ma).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.power_mw).sum::<f64>() / n;
+    let sxx: f64 = points.iter().map(|p| (p.current_ma - mean_x).powi(2)).sum();
+    let sxy: f64 = points.iter().map(|p| (p.current_ma - mean_x) * (p.power_mw - mean_y)).sum();
+    let syy: f64 = points.iter().map(|p| (p.power_mw - mean_y).powi(2)).sum();
+    if sxx == 0.0 {
+        return None;
+    }
+    let slope = sxy / sxx;
+    let intercept = mean_y - slope * mean_x;
+
+    let residuals: Vec<FitResidual> = points
+        .iter()
+        .map(|p| FitResidual {
+            current_ma: p.current_ma,
+            residual_mw: p.power_mw - (slope * p.current_ma + intercept),
+        })
+        .collect();
+    let ss_res: f64 = residuals.iter().map(|r| r.residual_mw.powi(2)).sum();
+    Some(LinearFit {
+        // mW/mA is W/A
+        slope_w_per_a: slope,
+        intercept_mw: intercept,
+        r_squared: if syy > 0.0 { 1.0 - ss_res / syy } else { 1.0 },
+        rms_residual_mw: (ss_res / n).sqrt(),
+        residuals,
+    })
+}
+
+/// Central-difference derivative of `(x, y)` samples, at the inner samples.
+fn derivative(samples: &[(f64, f64)]) -> Vec<(f64, f64)> {
+    samples
+        .windows(3)
+        .filter(|w| w[2].0 > w[0].0)
+        .map(|w| (w[1].0, (w[2].1 - w[0].1) / (w[2].0 - w[0].0)))
+        .collect()
+}
+
+fn samples(points: &[LiPoint]) -> Vec<(f64, f64)> {
+    points.iter().map(|p| (p.current_ma, p.power_mw)).collect()
+}
+
+/// Current at which the slope dP/dI first reaches half of its maximum,
+/// interpolated between samples.
+fn first_derivative_threshold(points: &[LiPoint]) -> Option<f64> {
+    let slope = derivative(&samples(points));
+    let max = slope.iter().map(|&(_, d)| d).fold(f64::NEG_INFINITY, f64::max);
+    if slope.len() < MIN_POINTS || max <= 0.0 {
+        return None;
+    }
+    let half = max / 2.0;
+    if slope[0].1 >= half {
+        // Already lasing at the first point
+        return None;
+    }
+    slope.windows(2).find(|w| w[1].1 >= half).map(|w| {
+        let ((x0, d0), (x1, d1)) = (w[0], w[1]);
+        x0 + (half - d0) / (d1 - d0) * (x1 - x0)
+    })
+}
+
+/// Current at the peak of d²P/dI², refined with a parabola through the peak
+/// and its neighbours.
+fn second_derivative_threshold(points: &[LiPoint]) -> Option<f64> {
+    let curvature = derivative(&derivative(&samples(points)));
+    if curvature.len() < MIN_POINTS {
+        return None;
+    }
+    let (i, &(x, peak)) = curvature
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.1.total_cmp(&b.1))?;
+    if peak <= 0.0 {
+        return None;
+    }
+    if i == 0 || i + 1 == curvature.len() {
+        return Some(x);
+    }
+    Some(parabola_vertex(curvature[i - 1], curvature[i], curvature[i + 1]).unwrap_or(x))
+}
+
+/// x of the vertex of the parabola through three points.
+fn parabola_vertex((x0, y0): (f64, f64), (x1, y1): (f64, f64), (x2, y2): (f64, f64)) -> Option<f64> {
+    let denominator = (x0 - x1) * (x0 - x2) * (x1 - x2);
+    let a = (x2 * (y1 - y0) + x1 * (y0 - y2) + x0 * (y2 - y1)) / denominator;
+    let b = (x2 * x2 * (y0 - y1) + x1 * x1 * (y2 - y0) + x0 * x0 * (y1 - y2)) / denominator;
+    let vertex = -b / (2.0 * a);
+    // Keep the estimate between the neighbours
+    (a < 0.0 && (x0..=x2).contains(&vertex)).then_some(vertex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD_MA: f64 = 30.0;
+    const SLOPE_W_PER_A: f64 = 0.5;
+
+    /// Ideal diode: weak spontaneous emission below threshold, a straight
+    /// line above it.
+    fn ideal(current_ma: f64) -> f64 {
+        if current_ma < THRESHOLD_MA {
+            0.002 * current_ma
+        } else {
+            0.002 * THRESHOLD_MA + SLOPE_W_PER_A * (current_ma - THRESHOLD_MA)
+        }
+    }
+
+    /// Points every 2 mA from 0 to 100 mA.
+    fn curve(power_mw: impl Fn(f64) -> f64) -> Vec<LiPoint> {
+        (0..=50)
+            .map(|step| {
+                let current_ma = 2.0 * step as f64;
+                LiPoint { current_ma, power_mw: power_mw(current_ma) }
+            })
+            .collect()
+    }
+
+    fn analyze(points: &[LiPoint], settings: &AnalysisSettings) -> CurveAnalysis {
+        analyze_curve(CurveSource::PowerMeter, Some(1), Some(1), points, settings)
+    }
+
+    fn record(point: &LiPoint) -> MeasurementRecord {
+        MeasurementRecord {
+            timestamp: String::new(),
+            current_ma: Some(point.current_ma),
+            power_setpoint_mw: None,
+            measured_current_ma: point.current_ma,
+            voltage_v: 1.5,
+            power_dbm: Some(10.0 * point.power_mw.log10()),
+            status: None,
+            module: Some(1),
+            port: Some(1),
+            monitor_current_ma: None,
+            monitor_power_dbm: None,
+        }
+    }
+
+    fn annotated(screening: &Screening, kind: AnnotationKind) -> Vec<f64> {
+        screening.annotations.iter().filter(|a| a.kind == kind).map(|a| a.current_ma).collect()
+    }
+
+    #[test]
+    fn finds_threshold_and_slope() {
+        let analysis = analyze(&curve(ideal), &AnalysisSettings::default());
+
+        assert_eq!(analysis.points, 51);
+        assert!((analysis.slope_efficiency_w_per_a.unwrap() - SLOPE_W_PER_A).abs() < 1e-9);
+        let threshold = &analysis.threshold;
+        assert!((threshold.linear_fit_ma.unwrap() - THRESHOLD_MA).abs() < 0.5);
+        assert!((threshold.first_derivative_ma.unwrap() - THRESHOLD_MA).abs() <= 2.0);
+        assert!((threshold.second_derivative_ma.unwrap() - THRESHOLD_MA).abs() <= 2.0);
+        assert_eq!(analysis.current_at_max_power_ma, Some(100.0));
+
+        let screening = analysis.screening.unwrap();
+        assert!(screening.passed);
+        assert_eq!(screening.rollover_onset_ma, None);
+        assert_eq!(annotated(&screening, AnnotationKind::PeakPower), vec![100.0]);
+    }
+
+    #[test]
+    fn flat_curve_has_no_lasing_region() {
+        let analysis = analyze(&curve(|_| 0.001), &AnalysisSettings::default());
+
+        assert_eq!(analysis.fit, None);
+        assert_eq!(analysis.slope_efficiency_w_per_a, None);
+        assert_eq!(analysis.threshold, ThresholdEstimates::default());
+        assert_eq!(analysis.screening, None);
+    }
+
+    #[test]
+    fn too_few_points_give_no_estimates() {
+        let points = curve(ideal);
+        let analysis = analyze(&points[40..42], &AnalysisSettings::default());
+
+        assert_eq!(analysis.points, 2);
+        assert_eq!(analysis.fit, None);
+        assert_eq!(analysis.threshold, ThresholdEstimates::default());
+        assert_eq!(analysis.screening, None);
+        assert_eq!(analysis.max_power_mw, Some(points[41].power_mw));
+
+        assert_eq!(analyze(&[], &AnalysisSettings::default()).max_power_mw, None);
+    }
+
+    #[test]
+    fn sorts_records_by_current() {
+        let points = curve(ideal);
+        let sorted: Vec<MeasurementRecord> = points.iter().map(record).collect();
+        // Every 7th point, wrapping around: the order a sweep never produces
+        let shuffled: Vec<MeasurementRecord> = (0..points.len()).map(|i| record(&points[i * 7 % points.len()])).collect();
+
+        let settings = AnalysisSettings::default();
+        let analysis = analyze_sweep(&shuffled, &settings);
+        assert_eq!(analysis, analyze_sweep(&sorted, &settings));
+        assert_eq!(analysis.len(), 1);
+        assert!((analysis[0].threshold.linear_fit_ma.unwrap() - THRESHOLD_MA).abs() < 0.5);
+    }
+
+    #[test]
+    fn flags_kink() {
+        // The slope drops to 0.3 W/A at 60 mA, not far enough for rollover
+        let kinked = |current_ma: f64| ideal(current_ma.min(60.0)) + 0.3 * (current_ma - 60.0).max(0.0);
+        let screening = analyze(&curve(kinked), &AnalysisSettings::default()).screening.unwrap();
+
+        assert!(!screening.kink_passed);
+        assert!(!screening.passed);
+        assert!(screening.rollover_passed);
+        let kinks = annotated(&screening, AnnotationKind::Kink);
+        assert_eq!(kinks.len(), 1);
+        assert!((kinks[0] - 60.0).abs() <= 2.0);
+    }
+
+    #[test]
+    fn single_high_reading_is_not_rollover() {
+        // A point-to-point derivative peaks just below this point and drops
+        // to a third of that just above it
+        let mut points = curve(ideal);
+        points[35].power_mw += 1.0;
+        let screening = analyze(&points, &AnalysisSettings::default()).screening.unwrap();
+
+        assert_eq!(screening.rollover_onset_ma, None);
+        assert!(screening.rollover_passed);
+    }
+
+    #[test]
+    fn finds_thermal_rollover() {
+        // dP/dI falls by 0.01 W/A per mA above threshold and the power peaks
+        // at 80 mA. The steepest fitted slope, 0.47 W/A around 33 mA, halves
+        // at 56.5 mA
+        let rollover = |current_ma: f64| {
+            let above = (current_ma - THRESHOLD_MA).max(0.0);
+            ideal(current_ma) - 0.005 * above * above
+        };
+        let settings = AnalysisSettings::default();
+        let analysis = analyze(&curve(rollover), &settings);
+        let screening = analysis.screening.unwrap();
+
+        assert!((screening.rollover_onset_ma.unwrap() - 56.5).abs() < 0.5);
+        assert!(!screening.rollover_passed);
+        assert!(!screening.peak_power_passed);
+        assert_eq!(analysis.current_at_max_power_ma, Some(80.0));
+
+        let settings = AnalysisSettings {
+            min_rollover_current_ma: Some(50.0),
+            min_peak_power_current_ma: Some(75.0),
+            ..settings
+        };
+        let screening = analyze(&curve(rollover), &settings).screening.unwrap();
+        assert!(screening.rollover_passed);
+        assert!(screening.peak_power_passed);
+    }
+
+    #[test]
+    fn peak_power_allows_noise_at_the_end() {
+        // The last point reads 1 % below the one before it
+        let mut points = curve(ideal);
+        let last = points.len() - 1;
+        points[last].power_mw = 0.99 * points[last - 1].power_mw;
+
+        let settings = AnalysisSettings::default();
+        assert!(analyze(&points, &settings).screening.unwrap().peak_power_passed);
+
+        let strict = AnalysisSettings { peak_power_tolerance: 0.005, ..settings };
+        assert!(!analyze(&points, &strict).screening.unwrap().peak_power_passed);
+    }
+}
@@ -0,0 +1,425 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::analysis::AnalysisSettings;
+use crate::devices::{PhotodiodePolarity, TransportSpec};
+use crate::error::{LabError, Result};
+use crate::safety::{Range, SafetyRules};
+
+/// Default location of the lab configuration, relative to the working directory.
+const DEFAULT_CONFIG_PATH: &str = "lab_config.json";
+
+/// Bench-specific settings loaded from `lab_config.json` (or the file named by
+/// `OPTICAL_LAB_CONFIG`). Missing fields fall back to the defaults below.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct LabConfig {
+    /// Use the simulated instruments instead of the hardware
+    pub simulate: bool,
+    pub instruments: Instruments,
+    pub experiment: ExperimentSettings,
+    pub discovery: DiscoveryConfig,
+    pub monitor: MonitorConfig,
+    pub safety: SafetyRules,
+    /// Laser diodes that can be mounted on the CLD1015, by name
+    pub profiles: HashMap<String, LaserProfile>,
+    /// Name of the diode currently mounted, if any
+    pub active_profile: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Instruments {
+    pub cld1015: InstrumentConfig,
+    pub mpm210h: InstrumentConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InstrumentConfig {
+    pub transport: TransportConfig,
+    /// Response timeout of the link
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Response timeouts by command header, e.g. `{ "READ?": 5000 }`
+    #[serde(default)]
+    pub command_timeouts_ms: HashMap<String, u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TransportConfig {
+    Visa { resource: String },
+    Tcp { address: String },
+    Serial { port: String, baud_rate: u32 },
+}
+
+/// Defaults applied when connecting instruments and running experiments.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ExperimentSettings {
+    /// Emission wavelength of the laser, programmed into the power meter
+    pub wavelength_nm: u32,
+    /// Settling time after each current step
+    pub stabilization_delay_ms: u64,
+    /// Time the power meter needs to complete a zeroing
+    pub zeroing_delay_ms: u64,
+    /// Largest deviation from the TEC setpoint counted as stable
+    pub temperature_tolerance_c: f64,
+    /// How long the temperature must stay stable before the laser is enabled
+    pub temperature_hold_ms: u64,
+    /// Longest wait for the temperature to settle
+    pub temperature_timeout_ms: u64,
+    /// Record the CLD1015 monitor photodiode next to the power meter
+    pub record_monitor_photodiode: bool,
+    /// Kink and rollover screening of the measured curves
+    pub analysis: AnalysisSettings,
+}
+
+/// Ratings of one laser diode. The active profile tightens the safety rules
+/// and sets the wavelength the power meter is programmed to.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LaserProfile {
+    /// Absolute maximum drive current of the diode
+    pub max_current_ma: f64,
+    /// Expected threshold current, used to sanity-check sweeps
+    pub threshold_ma: Option<f64>,
+    pub wavelength_nm: u32,
+    /// Temperature the TEC should hold the diode at
+    pub tec_setpoint_c: f64,
+    /// Current limit of the TEC element in the mount
+    pub tec_current_limit_a: Option<f64>,
+    /// Diode temperature range in which the laser may be enabled
+    pub temperature_range_c: Range,
+    /// Responsivity of the monitor photodiode in the diode package
+    pub monitor_responsivity_a_per_w: Option<f64>,
+    /// Grounded terminal of the monitor photodiode, for constant-power feedback
+    pub monitor_polarity: Option<PhotodiodePolarity>,
+    /// Highest monitor photodiode power a power sweep may regulate to
+    pub max_power_mw: Option<f64>,
+}
+
+/// Where `discover_instruments` looks for networked instruments.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    /// Single hosts (`192.168.1.161`) or subnets (`192.168.1.0/24`)
+    pub hosts: Vec<String>,
+    pub ports: Vec<u16>,
+    /// Connect and `*IDN?` timeout per candidate
+    pub timeout_ms: u64,
+}
+
+/// Background sampling of the instruments for the `device-telemetry` event.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct MonitorConfig {
+    pub enabled: bool,
+    pub interval_ms: u64,
+}
+
+fn default_timeout_ms() -> u64 {
+    2000
+}
+
+impl Default for Instruments {
+    fn default() -> Self {
+        Instruments {
+            cld1015: InstrumentConfig {
+                transport: TransportConfig::Visa {
+                    resource: "USB0::4883::32847::M01053290::0::INSTR".into(),
+                },
+                timeout_ms: default_timeout_ms(),
+                command_timeouts_ms: HashMap::new(),
+            },
+            mpm210h: InstrumentConfig {
+                transport: TransportConfig::Tcp {
+                    address: "192.168.1.161:5000".into(),
+                },
+                timeout_ms: default_timeout_ms(),
+                // Reading every port of a module takes noticeably longer than other queries
+                command_timeouts_ms: HashMap::from([("READ?".to_string(), 5000)]),
+            },
+        }
+    }
+}
+
+impl Default for ExperimentSettings {
+    fn default() -> Self {
+        ExperimentSettings {
+            wavelength_nm: 980,
+            stabilization_delay_ms: 20, // enough for cld1015 and mpm210h
+            zeroing_delay_ms: 3000, // as per MPM-210H documentation
+            temperature_tolerance_c: 0.1,
+            temperature_hold_ms: 5000,
+            temperature_timeout_ms: 120_000,
+            record_monitor_photodiode: false,
+            analysis: AnalysisSettings::default(),
+        }
+    }
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            hosts: vec!["192.168.1.0/24".into()],
+            ports: vec![5000], // MPM-210H
+            timeout_ms: 300,
+        }
+    }
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        MonitorConfig {
+            enabled: true,
+            interval_ms: 1000,
+        }
+    }
+}
+
+impl MonitorConfig {
+    /// Sampling period, or `None` when the monitor is disabled.
+    pub fn interval(&self) -> Option<Duration> {
+        self.enabled.then(|| Duration::from_millis(self.interval_ms))
+    }
+}
+
+impl LabConfig {
+    /// `OPTICAL_LAB_CONFIG` if set, `lab_config.json` otherwise.
+    pub fn path() -> PathBuf {
+        std::env::var_os("OPTICAL_LAB_CONFIG")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH))
+    }
+
+    /// Reads the configuration from `path()`. A missing file yields the defaults.
+    pub fn load() -> Result<LabConfig> {
+        let path = Self::path();
+        if !path.exists() {
+            info!("No lab config at {:?}, using defaults", path);
+            return Ok(LabConfig::default());
+        }
+        let text = std::fs::read_to_string(&path)?;
+        let config: LabConfig = serde_json::from_str(&text)
+            .map_err(|e| LabError::ParseError(format!("Lab config {:?}: {}", path, e)))?;
+        config.validate()?;
+        info!("Loaded lab config from {:?}", path);
+        Ok(config)
+    }
+
+    /// Profile named by `active_profile`. `validate` guarantees it exists.
+    pub fn active_profile(&self) -> Option<(&str, &LaserProfile)> {
+        let name = self.active_profile.as_deref()?;
+        self.profiles.get(name).map(|profile| (name, profile))
+    }
+
+    /// Experiment settings with the wavelength of the active profile.
+    pub fn experiment_settings(&self) -> ExperimentSettings {
+        let mut settings = self.experiment.clone();
+        if let Some((_, profile)) = self.active_profile() {
+            settings.wavelength_nm = profile.wavelength_nm;
+        }
+        settings
+    }
+
+    /// Safety rules narrowed by the active profile: the lower of the two
+    /// current limits, and the profile's temperature range.
+    pub fn safety_rules(&self) -> SafetyRules {
+        let mut rules = self.safety.clone();
+        if let Some((_, profile)) = self.active_profile() {
+            rules.max_current_ma = rules.max_current_ma.min(profile.max_current_ma);
+            rules.temperature_window_c = Some(profile.temperature_range_c);
+        }
+        rules
+    }
+
+    /// Makes `name` the active profile, or clears it with `None`.
+    pub fn select_profile(&mut self, name: Option<String>) -> Result<()> {
+        if let Some(name) = &name {
+            if !self.profiles.contains_key(name) {
+                return Err(LabError::InvalidParameter(format!("Unknown laser profile '{}'", name)));
+            }
+        }
+        self.active_profile = name;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.experiment.wavelength_nm == 0 {
+            return Err(LabError::InvalidParameter("Lab config: wavelength_nm must be positive".into()));
+        }
+        if self.experiment.temperature_tolerance_c <= 0.0 {
+            return Err(LabError::InvalidParameter("Lab config: temperature_tolerance_c must be positive".into()));
+        }
+        let analysis = &self.experiment.analysis;
+        if analysis.kink_tolerance <= 0.0 {
+            return Err(LabError::InvalidParameter("Lab config: analysis kink_tolerance must be positive".into()));
+        }
+        if analysis.rollover_slope_fraction <= 0.0 || analysis.rollover_slope_fraction >= 1.0 {
+            return Err(LabError::InvalidParameter(
+                "Lab config: analysis rollover_slope_fraction must lie between 0 and 1".into(),
+            ));
+        }
+        if !(0.0..1.0).contains(&analysis.peak_power_tolerance) {
+            return Err(LabError::InvalidParameter(
+                "Lab config: analysis peak_power_tolerance must lie between 0 and 1".into(),
+            ));
+        }
+        if self.safety.max_current_ma <= 0.0 {
+            return Err(LabError::InvalidParameter("Lab config: safety max_current_ma must be positive".into()));
+        }
+        if self.safety.max_slew_ma_per_ms.is_some_and(|slew| slew <= 0.0) {
+            return Err(LabError::InvalidParameter("Lab config: safety max_slew_ma_per_ms must be positive".into()));
+        }
+        if self.safety.max_power_slew_mw_per_ms.is_some_and(|slew| slew <= 0.0) {
+            return Err(LabError::InvalidParameter(
+                "Lab config: safety max_power_slew_mw_per_ms must be positive".into(),
+            ));
+        }
+        if self.monitor.interval_ms < 100 {
+            return Err(LabError::InvalidParameter("Lab config: monitor interval_ms must be at least 100".into()));
+        }
+        if let Some(name) = &self.active_profile {
+            if !self.profiles.contains_key(name) {
+                return Err(LabError::InvalidParameter(format!("Lab config: unknown active_profile '{}'", name)));
+            }
+        }
+        for (name, profile) in &self.profiles {
+            profile.validate().map_err(|reason| {
+                LabError::InvalidParameter(format!("Lab config: profile '{}': {}", name, reason))
+            })?;
+        }
+        for (name, instrument) in [("cld1015", &self.instruments.cld1015), ("mpm210h", &self.instruments.mpm210h)] {
+            if instrument.timeout_ms == 0 {
+                return Err(LabError::InvalidParameter(format!("Lab config: {} timeout_ms must be positive", name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ExperimentSettings {
+    pub fn temperature_hold(&self) -> Duration {
+        Duration::from_millis(self.temperature_hold_ms)
+    }
+
+    pub fn temperature_timeout(&self) -> Duration {
+        Duration::from_millis(self.temperature_timeout_ms)
+    }
+}
+
+impl LaserProfile {
+    fn validate(&self) -> std::result::Result<(), String> {
+        if self.max_current_ma <= 0.0 {
+            return Err("max_current_ma must be positive".into());
+        }
+        if self.wavelength_nm == 0 {
+            return Err("wavelength_nm must be positive".into());
+        }
+        if let Some(threshold_ma) = self.threshold_ma {
+            if threshold_ma <= 0.0 || threshold_ma >= self.max_current_ma {
+                return Err("threshold_ma must lie between 0 and max_current_ma".into());
+            }
+        }
+        let range = self.temperature_range_c;
+        if range.min >= range.max {
+            return Err("temperature_range_c min must be below max".into());
+        }
+        if !range.contains(self.tec_setpoint_c) {
+            return Err("tec_setpoint_c must lie inside temperature_range_c".into());
+        }
+        if self.tec_current_limit_a.is_some_and(|amps| amps <= 0.0) {
+            return Err("tec_current_limit_a must be positive".into());
+        }
+        if self.monitor_responsivity_a_per_w.is_some_and(|responsivity| responsivity <= 0.0) {
+            return Err("monitor_responsivity_a_per_w must be positive".into());
+        }
+        if self.max_power_mw.is_some_and(|mw| mw <= 0.0) {
+            return Err("max_power_mw must be positive".into());
+        }
+        Ok(())
+    }
+
+    /// Refuses sweeps that would exceed the diode rating. A sweep that ends
+    /// below the threshold estimate is allowed but logged, since it will not
+    /// show lasing.
+    pub fn check_sweep(&self, start_ma: f64, stop_ma: f64) -> Result<()> {
+        if stop_ma > self.max_current_ma {
+            return Err(LabError::InvalidParameter(format!(
+                "Stop current {} mA exceeds the {} mA rating of the laser profile",
+                stop_ma, self.max_current_ma
+            )));
+        }
+        if start_ma < 0.0 {
+            return Err(LabError::InvalidParameter("Start current must not be negative".into()));
+        }
+        if let Some(threshold_ma) = self.threshold_ma {
+            if stop_ma < threshold_ma {
+                warn!(
+                    "Sweep stops at {} mA, below the expected threshold of {} mA",
+                    stop_ma, threshold_ma
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuses power sweeps without a configured monitor photodiode: the
+    /// controller would regulate on a feedback signal it cannot interpret and
+    /// could drive the diode up to the current limit. The stop setpoint must
+    /// not exceed the profile's `max_power_mw`.
+    pub fn check_power_sweep(&self, start_mw: f64, stop_mw: f64) -> Result<()> {
+        if self.monitor_responsivity_a_per_w.is_none() || self.monitor_polarity.is_none() {
+            return Err(LabError::InvalidParameter(
+                "A power sweep needs the monitor photodiode responsivity and polarity in the laser profile".into(),
+            ));
+        }
+        if let Some(max_mw) = self.max_power_mw {
+            if stop_mw > max_mw {
+                return Err(LabError::InvalidParameter(format!(
+                    "Stop power {} mW exceeds the {} mW rating of the laser profile",
+                    stop_mw, max_mw
+                )));
+            }
+        }
+        if start_mw < 0.0 {
+            return Err(LabError::InvalidParameter("Start power must not be negative".into()));
+        }
+        Ok(())
+    }
+}
+
+impl InstrumentConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn transport_spec(&self) -> TransportSpec {
+        let timeout = self.timeout();
+        match &self.transport {
+            TransportConfig::Visa { resource } => TransportSpec::Visa {
+                resource: resource.clone(),
+                timeout,
+            },
+            TransportConfig::Tcp { address } => TransportSpec::Tcp {
+                address: address.clone(),
+                timeout,
+            },
+            TransportConfig::Serial { port, baud_rate } => TransportSpec::Serial {
+                port: port.clone(),
+                baud_rate: *baud_rate,
+                timeout,
+            },
+        }
+    }
+
+    /// Command timeouts as `(header, timeout)` pairs.
+    pub fn command_timeouts(&self) -> impl Iterator<Item = (&str, Duration)> {
+        self.command_timeouts_ms
+            .iter()
+            .map(|(header, ms)| (header.as_str(), Duration::from_millis(*ms)))
+    }
+}
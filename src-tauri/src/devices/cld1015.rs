@@ -1,134 +1,489 @@
 #![allow(unused)]
 
-use std::ffi::CString;
-use std::io::{BufRead, BufReader, Write};
-use std::time::Duration;
-use visa_rs::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tracing::{info, warn, error};
 
-pub struct CLD1015 {
-    device: Option<Instrument>,
-    resource_string: String,
+use super::connection::{Connectable, ConnectionState};
+use super::LaserDriver;
+use super::simulated_cld1015::SimulatedCLD1015;
+use super::transport::{ScpiTransport, TransportSpec, STB_ERROR_QUEUE};
+use crate::error::{LabError, Result};
+use crate::monitor::{self, LaserTelemetry};
+use crate::safety::{SafetyRule, SafetySupervisor};
+
+/// Time between the intermediate setpoints of a current ramp.
+const RAMP_STEP_INTERVAL: Duration = Duration::from_millis(10);
+/// How often `wait_for_temperature_stable` reads the temperature.
+const TEMPERATURE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// Largest accepted difference between the programmed and read-back current limit.
+const LIMIT_TOLERANCE_AMPS: f64 = 1e-4;
+
+/// Hardware protections of the CLD1015. A tripped protection switches the
+/// laser output off until the cause is cleared.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Protection {
+    /// Interlock loop of the laser mount is open
+    Interlock,
+    OverTemperature,
+    /// No diode, or a broken connection, on the laser output
+    OpenCircuit,
+}
+
+impl Protection {
+    pub const ALL: [Protection; 3] = [Protection::Interlock, Protection::OverTemperature, Protection::OpenCircuit];
+
+    fn query(self) -> &'static str {
+        match self {
+            Protection::Interlock => "OUTPut:PROTection:INTLock:TRIPped?",
+            Protection::OverTemperature => "OUTPut:PROTection:OTEMp:TRIPped?",
+            Protection::OpenCircuit => "OUTPut:PROTection:OCIRcuit:TRIPped?",
+        }
+    }
 }
 
-// Helper function to convert IO errors to VISA errors
-fn io_to_vs_err(err: std::io::Error) -> visa_rs::Error {
-    visa_rs::io_to_vs_err(err)
+/// What the laser output is regulated to.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OperatingMode {
+    /// Constant current (ACC), the default
+    Current,
+    /// Constant power (APC), regulated on the monitor photodiode
+    Power,
+}
+
+impl OperatingMode {
+    fn scpi(self) -> &'static str {
+        match self {
+            OperatingMode::Current => "CURRent",
+            OperatingMode::Power => "POWer",
+        }
+    }
+}
+
+impl fmt::Display for OperatingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OperatingMode::Current => "current",
+            OperatingMode::Power => "power",
+        })
+    }
+}
+
+/// Which terminal of the monitor photodiode is grounded in the laser package.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PhotodiodePolarity {
+    CathodeGrounded,
+    AnodeGrounded,
+}
+
+impl PhotodiodePolarity {
+    fn scpi(self) -> &'static str {
+        match self {
+            PhotodiodePolarity::CathodeGrounded => "CG",
+            PhotodiodePolarity::AnodeGrounded => "AG",
+        }
+    }
+}
+
+/// Progress of `wait_for_temperature_stable`, reported on every reading.
+#[derive(Serialize, Clone, Debug)]
+pub struct TemperatureProgress {
+    pub temperature_c: f64,
+    pub setpoint_c: f64,
+    pub tolerance_c: f64,
+    /// How long the temperature has stayed within tolerance
+    pub stable_ms: u64,
+    /// How long it has to stay there
+    pub hold_ms: u64,
+    pub elapsed_ms: u64,
+}
+
+impl fmt::Display for Protection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Protection::Interlock => "interlock",
+            Protection::OverTemperature => "over_temperature",
+            Protection::OpenCircuit => "open_circuit",
+        };
+        f.write_str(name)
+    }
+}
+
+pub struct CLD1015 {
+    transport: Option<Box<dyn ScpiTransport>>,
+    spec: TransportSpec,
+    // Response timeouts by command header; others use the link default
+    command_timeouts: HashMap<String, Duration>,
+    // Set when the link failed while connected, cleared by connect/disconnect
+    lost: bool,
+    safety: Arc<SafetySupervisor>,
+    // Last current written or read back; `None` when unknown, e.g. after reconnecting
+    setpoint_amps: Option<f64>,
+    // Same for the constant-power setpoint
+    power_setpoint_watts: Option<f64>,
 }
 
 impl CLD1015 {
-    pub fn enable_tec(&mut self) -> visa_rs::Result<()> {
+    pub fn enable_tec(&mut self) -> Result<()> {
         info!("Enabling TEC");
         self.write("OUTPut2:STATe ON")
     }
     
     pub fn new(resource_string: &str) -> Self {
         info!("Initializing CLD1015 with resource string: {}", resource_string);
+        Self::with_transport(TransportSpec::Visa {
+            resource: resource_string.to_string(),
+            timeout: Duration::from_secs(2),
+        })
+    }
+
+    /// Creates a driver that talks to an in-process simulated controller
+    /// instead of a VISA instrument.
+    pub fn simulated(simulator: SimulatedCLD1015) -> Self {
+        Self::with_transport(TransportSpec::SimulatedCLD1015(simulator))
+    }
+
+    /// Creates a driver that reaches the controller over an arbitrary link.
+    pub fn with_transport(spec: TransportSpec) -> Self {
+        info!("Initializing CLD1015 over {}", spec);
         CLD1015 {
-            device: None,
-            resource_string: resource_string.to_string(),
+            transport: None,
+            spec,
+            command_timeouts: HashMap::new(),
+            lost: false,
+            safety: Arc::new(SafetySupervisor::default()),
+            setpoint_amps: None,
+            power_setpoint_watts: None,
         }
     }
 
-    pub fn connect(&mut self) -> visa_rs::Result<String> {
-        let rm = DefaultRM::new()?;
-        let resource = CString::new(self.resource_string.clone()).unwrap();
-        let device = rm.open(
-            &resource.into(),
-            AccessMode::NO_LOCK,
-            Duration::from_secs(1),
-        )?;
-        self.device = Some(device);
+    /// Shares the supervisor whose rules gate every current and output change.
+    pub fn set_safety(&mut self, safety: Arc<SafetySupervisor>) {
+        self.safety = safety;
+    }
+
+    /// Overrides the response timeout for queries starting with `header`.
+    pub fn set_command_timeout(&mut self, header: &str, timeout: Duration) {
+        self.command_timeouts.insert(header.to_ascii_uppercase(), timeout);
+    }
+
+    fn timeout_for(&self, command: &str) -> Option<Duration> {
+        let header = command.split_whitespace().next().unwrap_or_default();
+        self.command_timeouts.get(&header.to_ascii_uppercase()).copied()
+    }
+
+    pub fn connect(&mut self) -> Result<String> {
+        self.transport = Some(self.spec.open()?);
+        // The output may have been left on, e.g. across a lost link, with a
+        // setpoint this driver no longer knows; nothing runs before it is off
+        self.write("OUTPut:STATe OFF").inspect_err(|_| self.transport = None)?;
+        info!(target: "audit", "CLD1015 connect over {}: laser output switched off", self.spec);
         // Identify the device
-        let id = self.query("*IDN?")?;
+        let id = self.query("*IDN?").inspect_err(|_| self.transport = None)?;
+        self.lost = false;
+        self.setpoint_amps = None;
+        self.power_setpoint_watts = None;
+        // Never run without the hardware limit matching the safety rules
+        self.program_current_limit().inspect_err(|_| self.transport = None)?;
         info!("CLD1015 connected successfully. IDN: {}", id);
         Ok(id)
     }
 
+    /// Switches the laser output off and closes the link. The link is closed
+    /// even if the laser could not be switched off, in which case that error
+    /// is returned.
+    pub fn disconnect(&mut self) -> Result<()> {
+        self.lost = false;
+        if !self.is_connected() {
+            return Ok(());
+        }
+        info!("Disconnecting CLD1015");
+        let result = self.set_laser_output(false);
+        if let Err(e) = &result {
+            error!("Failed to switch laser off before disconnecting: {}", e);
+        }
+        self.transport = None;
+        self.setpoint_amps = None;
+        self.power_setpoint_watts = None;
+        result
+    }
+
     pub fn is_connected(&self) -> bool {
-        self.device.is_some()
+        self.transport.is_some()
     }
 
-    pub fn write(&mut self, command: &str) -> visa_rs::Result<()> {
-        if let Some(device) = &mut self.device {
-            let command_with_newline = format!("{}\n", command);
-            info!("Sending command: {}", command);
-            device.write_all(command_with_newline.as_bytes()).map_err(io_to_vs_err)?;
-            Ok(())
-        } else {
-            error!("Attempted to write to CLD1015 but device is not connected");
-            Err(visa_rs::io_to_vs_err(std::io::Error::new(
-                std::io::ErrorKind::NotConnected,
-                "Device not connected",
-            )))
+    /// The link the driver connects over.
+    pub fn transport_spec(&self) -> &TransportSpec {
+        &self.spec
+    }
+
+    /// Queries `*IDN?` to check the instrument still answers. A failed ping
+    /// marks the link as lost.
+    pub fn ping(&mut self) -> Result<()> {
+        match self.query("*IDN?") {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                if self.transport.take().is_some() {
+                    warn!("CLD1015 lost: {}", e);
+                    self.lost = true;
+                }
+                Err(e)
+            }
         }
     }
 
-    pub fn read(&mut self) -> visa_rs::Result<String> {
-        if let Some(device) = &mut self.device {
-            let mut response = String::new();
-            let bytes_read = BufReader::new(device).read_line(&mut response).map_err(io_to_vs_err)?;
-            let trimmed = response.trim().to_string();
-            info!("Received response: {}", trimmed);
-            Ok(trimmed)
-        } else {
-            error!("Attempted to read from CLD1015 but device is not connected");
-            Err(visa_rs::io_to_vs_err(std::io::Error::new(
-                std::io::ErrorKind::NotConnected,
-                "Device not connected",
-            )))
+    /// Drops the link after a transport failure so it can be reconnected.
+    fn check_link<T>(&mut self, result: Result<T>) -> Result<T> {
+        if let Err(LabError::Io(e)) = &result {
+            error!("CLD1015 link failed: {}", e);
+            self.transport = None;
+            self.lost = true;
+            self.setpoint_amps = None;
+            self.power_setpoint_watts = None;
         }
+        result
+    }
+
+    fn transport(&mut self) -> Result<&mut Box<dyn ScpiTransport>> {
+        self.transport.as_mut().ok_or_else(|| {
+            error!("Attempted to talk to CLD1015 but device is not connected");
+            LabError::NotConnected {
+                device: "CLD1015".into(),
+            }
+        })
+    }
+
+    pub fn write(&mut self, command: &str) -> Result<()> {
+        let result = self.transport()?.write_line(command);
+        self.check_link(result)
     }
 
-    pub fn query(&mut self, command: &str) -> visa_rs::Result<String> {
-        self.write(command)?;
-        self.read()
+    pub fn read(&mut self) -> Result<String> {
+        let result = self.transport()?.read_line();
+        self.check_link(result)
     }
 
-    pub fn get_tec_state(&mut self) -> visa_rs::Result<bool> {
+    pub fn query(&mut self, command: &str) -> Result<String> {
+        let timeout = self.timeout_for(command);
+        let transport = self.transport()?;
+        let timeout = timeout.unwrap_or_else(|| transport.timeout());
+        let result = transport.query_timeout(command, timeout);
+        self.check_link(result)
+    }
+
+    pub fn get_tec_state(&mut self) -> Result<bool> {
         let response = self.query("OUTPut2:STATe?")?;
         Ok(response.eq_ignore_ascii_case("ON") || response == "1")
     }
 
-    pub fn set_current_mode(&mut self) -> visa_rs::Result<()> {
-        self.write("SOURce:FUNCtion:MODE CURRent")
+    pub fn set_current_mode(&mut self) -> Result<()> {
+        self.set_operating_mode(OperatingMode::Current)
+    }
+
+    /// Selects constant-current or constant-power operation. The controller
+    /// only changes mode with the output off, so this is refused otherwise.
+    pub fn set_operating_mode(&mut self, mode: OperatingMode) -> Result<()> {
+        if self.get_laser_output()? {
+            return Err(LabError::InvalidParameter(
+                "Switch the laser output off before changing the operating mode".into(),
+            ));
+        }
+        info!("Selecting constant {} mode", mode);
+        self.write(&format!("SOURce:FUNCtion:MODE {}", mode.scpi()))
+    }
+
+    pub fn get_operating_mode(&mut self) -> Result<OperatingMode> {
+        let response = self.query("SOURce:FUNCtion:MODE?")?;
+        let mode = response.to_ascii_uppercase();
+        if mode.starts_with("CURR") {
+            Ok(OperatingMode::Current)
+        } else if mode.starts_with("POW") {
+            Ok(OperatingMode::Power)
+        } else {
+            Err(LabError::ParseError(format!("Unknown operating mode: {}", response)))
+        }
+    }
+
+    /// Sets the optical power regulated to in constant-power mode, in W as
+    /// seen by the monitor photodiode. The drive current this takes is only
+    /// bounded by the hardware current limit, so a change larger than
+    /// `max_power_slew_mw_per_ms` allows is ramped like in `set_current`.
+    pub fn set_power(&mut self, power_watts: f64) -> Result<()> {
+        self.safety.check_power_setpoint(power_watts)?;
+        if let Some(slew) = self.safety.rules().max_power_slew_mw_per_ms {
+            let max_step_watts = slew * RAMP_STEP_INTERVAL.as_millis() as f64 / 1000.0;
+            let from_watts = match self.power_setpoint_watts {
+                Some(watts) => watts,
+                None => self.get_power_setpoint()?,
+            };
+            self.ramp("power setpoint", "W", from_watts, power_watts, max_step_watts, Self::write_power)?;
+        }
+        info!("Setting power setpoint to {:.3} mW", power_watts * 1000.0);
+        self.write_power(power_watts)
+    }
+
+    fn write_power(&mut self, power_watts: f64) -> Result<()> {
+        self.power_setpoint_watts = None;
+        self.write(&format!("SOURce:POWer:LEVel:IMMediate:AMPLitude {}", power_watts))?;
+        self.power_setpoint_watts = Some(power_watts);
+        Ok(())
+    }
+
+    pub fn get_power_setpoint(&mut self) -> Result<f64> {
+        let response = self.query("SOURce:POWer:LEVel:IMMediate:AMPLitude?")?;
+        let power_watts = response
+            .parse::<f64>()
+            .map_err(|_| LabError::ParseError(format!("Failed to parse power setpoint: {}", response)))?;
+        self.power_setpoint_watts = Some(power_watts);
+        Ok(power_watts)
     }
     
-    pub fn set_current(&mut self, current_amps: f64) -> visa_rs::Result<()> {
-        const MAX_SAFE_CURRENT_AMPS: f64 = 1.5;
-        if current_amps > MAX_SAFE_CURRENT_AMPS {
-            warn!("Attempted to set current above safe limit: {} A", current_amps);
-            return Err(visa_rs::io_to_vs_err(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                format!("Requested current {} A exceeds the 1.5 A safety limit", current_amps),
-            )));
+    /// Sets the laser current. A change larger than the configured slew
+    /// allows in one `RAMP_STEP_INTERVAL` is applied as a timed ramp of
+    /// intermediate setpoints, so the call blocks until the ramp is done.
+    pub fn set_current(&mut self, current_amps: f64) -> Result<()> {
+        self.safety.check_current(current_amps)?;
+        if let Some(slew) = self.safety.rules().max_slew_ma_per_ms {
+            let max_step_amps = slew * RAMP_STEP_INTERVAL.as_millis() as f64 / 1000.0;
+            let from_amps = match self.setpoint_amps {
+                Some(amps) => amps,
+                None => self.get_current()?,
+            };
+            self.ramp("current", "A", from_amps, current_amps, max_step_amps, Self::write_current)?;
         }
         info!("Setting current to {:.3} A", current_amps);
-        self.write(&format!("SOURce:CURRent:LEVel:IMMediate:AMPLitude {}", current_amps))
+        self.write_current(current_amps)
     }
 
-    pub fn get_current(&mut self) -> visa_rs::Result<f64> {
+    /// Writes the intermediate setpoints from `from` to `to`, at most
+    /// `max_step` and `RAMP_STEP_INTERVAL` apart. The final setpoint is left
+    /// to the caller.
+    fn ramp(
+        &mut self,
+        what: &str,
+        unit: &str,
+        from: f64,
+        to: f64,
+        max_step: f64,
+        write: fn(&mut Self, f64) -> Result<()>,
+    ) -> Result<()> {
+        let steps = ((to - from).abs() / max_step).ceil() as usize;
+        if steps > 1 {
+            info!("Ramping {} from {:.4} {unit} to {:.4} {unit} in {} steps", what, from, to, steps);
+            for step in 1..steps {
+                write(self, from + (to - from) * step as f64 / steps as f64)?;
+                std::thread::sleep(RAMP_STEP_INTERVAL);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_current(&mut self, current_amps: f64) -> Result<()> {
+        self.setpoint_amps = None;
+        self.write(&format!("SOURce:CURRent:LEVel:IMMediate:AMPLitude {}", current_amps))?;
+        self.setpoint_amps = Some(current_amps);
+        Ok(())
+    }
+
+    pub fn get_current(&mut self) -> Result<f64> {
         let response = self.query("SOURce:CURRent:LEVel:IMMediate:AMPLitude?")?;
         info!("Queried current: {} A", response);
-        response.parse::<f64>().map_err(|_| visa_rs::io_to_vs_err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Failed to parse current value",
-        )))
+        let current_amps = response
+            .parse::<f64>()
+            .map_err(|_| LabError::ParseError(format!("Failed to parse current value: {}", response)))?;
+        self.setpoint_amps = Some(current_amps);
+        Ok(current_amps)
+    }
+
+    pub fn set_current_limit(&mut self, current_amps: f64) -> Result<()> {
+        info!("Setting current limit to {:.4} A", current_amps);
+        self.write(&format!("SOURce:CURRent:LIMit:AMPLitude {}", current_amps))
+    }
+
+    pub fn get_current_limit(&mut self) -> Result<f64> {
+        let response = self.query("SOURce:CURRent:LIMit:AMPLitude?")?;
+        response
+            .parse::<f64>()
+            .map_err(|_| LabError::ParseError(format!("Failed to parse current limit: {}", response)))
+    }
+
+    /// Programs the instrument's own current limit from the safety rules and
+    /// reads it back, so the diode stays protected even if a command bypasses
+    /// the software check.
+    pub fn program_current_limit(&mut self) -> Result<()> {
+        let limit_amps = self.safety.rules().max_current_ma / 1000.0;
+        self.set_current_limit(limit_amps)?;
+        let read_back = self.get_current_limit()?;
+        if (read_back - limit_amps).abs() > LIMIT_TOLERANCE_AMPS {
+            error!("CLD1015 current limit reads back {} A, expected {} A", read_back, limit_amps);
+            return Err(LabError::SafetyInterlock {
+                rule: SafetyRule::MaxCurrent.to_string(),
+                reason: format!(
+                    "Instrument current limit reads back {:.4} A instead of {:.4} A",
+                    read_back, limit_amps
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// Protections that are currently tripped.
+    pub fn tripped_protections(&mut self) -> Result<Vec<Protection>> {
+        let mut tripped = Vec::new();
+        for protection in Protection::ALL {
+            let response = self.query(protection.query())?;
+            if response.eq_ignore_ascii_case("ON") || response == "1" {
+                tripped.push(protection);
+            }
+        }
+        Ok(tripped)
     }
 
-    pub fn set_laser_output(&mut self, enabled: bool) -> visa_rs::Result<()> {
+    /// Fails with `LabError::ProtectionTripped` for the first tripped protection.
+    pub fn check_protection(&mut self) -> Result<()> {
+        match self.tripped_protections()?.first() {
+            Some(protection) => {
+                error!("CLD1015 protection tripped: {}", protection);
+                Err(LabError::ProtectionTripped {
+                    device: "CLD1015".into(),
+                    protection: protection.to_string(),
+                })
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Switches the output on or off. Before switching off, the current is
+    /// ramped down to zero; the output is switched off even if that fails.
+    pub fn set_laser_output(&mut self, enabled: bool) -> Result<()> {
         if enabled {
-            // Safety check: ensure TEC is ON before enabling laser
-            let tec_on = self.get_tec_state()?;
-            if !tec_on {
-                error!("Attempt to enable laser while TEC is OFF");
-                return Err(visa_rs::io_to_vs_err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Cannot enable laser: TEC is OFF",
-                )));
+            self.check_protection()?;
+            // The output comes on at whatever setpoint the active mode regulates to
+            match self.get_operating_mode()? {
+                OperatingMode::Current => {
+                    let current_amps = self.get_current()?;
+                    self.check_interlocks(current_amps)?;
+                }
+                OperatingMode::Power => {
+                    let power_watts = self.get_power_setpoint()?;
+                    self.check_power_interlocks(power_watts)?;
+                }
             }
             info!("Enabling laser output");
         } else {
+            let ramp_down = match self.get_operating_mode() {
+                Ok(OperatingMode::Power) => self.set_power(0.0),
+                _ => self.set_current(0.0),
+            };
+            if let Err(e) = ramp_down {
+                error!("Failed to ramp the setpoint down before disabling output: {}", e);
+            }
             info!("Disabling laser output");
         }
     
@@ -136,28 +491,499 @@ impl CLD1015 {
         self.write(&format!("OUTPut:STATe {}", state))
     }
 
-    pub fn get_laser_output(&mut self) -> visa_rs::Result<bool> {
+    /// Laser current actually flowing, as measured by the controller (zero
+    /// with the output off), in A.
+    pub fn measure_current(&mut self) -> Result<f64> {
+        let response = self.query("MEASure:CURRent?")?;
+        response
+            .parse::<f64>()
+            .map_err(|_| LabError::ParseError(format!("Failed to parse measured current: {}", response)))
+    }
+
+    /// Forward voltage across the laser diode, in V.
+    pub fn get_forward_voltage(&mut self) -> Result<f64> {
+        let response = self.query("MEASure:VOLTage?")?;
+        response
+            .parse::<f64>()
+            .map_err(|_| LabError::ParseError(format!("Failed to parse forward voltage: {}", response)))
+    }
+
+    /// Sets the responsivity of the monitor photodiode, used by the
+    /// controller to convert its current into optical power.
+    pub fn set_photodiode_responsivity(&mut self, amps_per_watt: f64) -> Result<()> {
+        info!("Setting monitor photodiode responsivity to {} A/W", amps_per_watt);
+        self.write(&format!("SENSe2:CORRection:POWer:PDIode:RESPonse {}", amps_per_watt))
+    }
+
+    pub fn get_photodiode_responsivity(&mut self) -> Result<f64> {
+        let response = self.query("SENSe2:CORRection:POWer:PDIode:RESPonse?")?;
+        response
+            .parse::<f64>()
+            .map_err(|_| LabError::ParseError(format!("Failed to parse photodiode responsivity: {}", response)))
+    }
+
+    /// Sets which photodiode terminal is grounded, so the controller reads
+    /// the feedback current with the right sign.
+    pub fn set_photodiode_polarity(&mut self, polarity: PhotodiodePolarity) -> Result<()> {
+        info!("Setting monitor photodiode polarity to {:?}", polarity);
+        self.write(&format!("INPut:PDIode:POLarity {}", polarity.scpi()))
+    }
+
+    pub fn get_photodiode_polarity(&mut self) -> Result<PhotodiodePolarity> {
+        let response = self.query("INPut:PDIode:POLarity?")?;
+        match response.to_ascii_uppercase().as_str() {
+            "CG" => Ok(PhotodiodePolarity::CathodeGrounded),
+            "AG" => Ok(PhotodiodePolarity::AnodeGrounded),
+            _ => Err(LabError::ParseError(format!("Unknown photodiode polarity: {}", response))),
+        }
+    }
+
+    /// Current of the monitor photodiode, in A.
+    pub fn measure_photodiode_current(&mut self) -> Result<f64> {
+        let response = self.query("MEASure:CURRent2?")?;
+        response
+            .parse::<f64>()
+            .map_err(|_| LabError::ParseError(format!("Failed to parse photodiode current: {}", response)))
+    }
+
+    /// Optical power seen by the monitor photodiode, in W. Only meaningful
+    /// once the responsivity has been set.
+    pub fn measure_photodiode_power(&mut self) -> Result<f64> {
+        let response = self.query("MEASure:POWer2?")?;
+        response
+            .parse::<f64>()
+            .map_err(|_| LabError::ParseError(format!("Failed to parse photodiode power: {}", response)))
+    }
+
+    /// Hard stop: switches the output off at once, then zeroes the current,
+    /// without ramping. For emergencies only.
+    pub fn emergency_off(&mut self) -> Result<()> {
+        warn!("Emergency laser shutdown, bypassing the current ramp");
+        let result = self.write("OUTPut:STATe OFF");
+        self.write_current(0.0).ok();
+        result
+    }
+
+    /// Sets the diode temperature the TEC regulates to.
+    pub fn set_tec_setpoint(&mut self, setpoint_c: f64) -> Result<()> {
+        self.safety.check_temperature_setpoint(setpoint_c)?;
+        info!("Setting TEC setpoint to {:.2} °C", setpoint_c);
+        self.write(&format!("SOURce2:TEMPerature:SPOint {}", setpoint_c))
+    }
+
+    pub fn get_tec_setpoint(&mut self) -> Result<f64> {
+        let response = self.query("SOURce2:TEMPerature:SPOint?")?;
+        response
+            .parse::<f64>()
+            .map_err(|_| LabError::ParseError(format!("Failed to parse TEC setpoint: {}", response)))
+    }
+
+    /// Limits the current the TEC may drive through the Peltier element.
+    pub fn set_tec_current_limit(&mut self, current_amps: f64) -> Result<()> {
+        info!("Setting TEC current limit to {:.3} A", current_amps);
+        self.write(&format!("SOURce2:CURRent:LIMit:AMPLitude {}", current_amps))
+    }
+
+    pub fn get_tec_current_limit(&mut self) -> Result<f64> {
+        let response = self.query("SOURce2:CURRent:LIMit:AMPLitude?")?;
+        response
+            .parse::<f64>()
+            .map_err(|_| LabError::ParseError(format!("Failed to parse TEC current limit: {}", response)))
+    }
+
+    /// Resistance of the thermistor in the laser mount, in Ω.
+    pub fn get_thermistor_resistance(&mut self) -> Result<f64> {
+        let response = self.query("MEASure:RESistance?")?;
+        response
+            .parse::<f64>()
+            .map_err(|_| LabError::ParseError(format!("Failed to parse thermistor resistance: {}", response)))
+    }
+
+    /// Blocks until the diode temperature has stayed within `tolerance_c` of
+    /// the TEC setpoint for `hold_time`, and returns the last reading.
+    /// `progress` is called with every reading; returning `false` from it
+    /// gives up with `LabError::Aborted`. Fails with `LabError::Timeout` if the
+    /// temperature has not settled after `timeout`.
+    pub fn wait_for_temperature_stable(
+        &mut self,
+        tolerance_c: f64,
+        hold_time: Duration,
+        timeout: Duration,
+        progress: &mut dyn FnMut(&TemperatureProgress) -> bool,
+    ) -> Result<f64> {
+        if !self.get_tec_state()? {
+            return Err(LabError::InvalidParameter("TEC is OFF, the temperature cannot stabilize".into()));
+        }
+        let setpoint_c = self.get_tec_setpoint()?;
+        info!("Waiting for {:.2} °C ± {} °C to hold for {:?}", setpoint_c, tolerance_c, hold_time);
+
+        let start = Instant::now();
+        let mut stable_since: Option<Instant> = None;
+        loop {
+            let temperature_c = self.get_temperature()?;
+            let now = Instant::now();
+            if (temperature_c - setpoint_c).abs() <= tolerance_c {
+                stable_since.get_or_insert(now);
+            } else {
+                stable_since = None;
+            }
+            let stable = stable_since.map(|since| now - since).unwrap_or_default();
+            let report = TemperatureProgress {
+                temperature_c,
+                setpoint_c,
+                tolerance_c,
+                stable_ms: stable.as_millis() as u64,
+                hold_ms: hold_time.as_millis() as u64,
+                elapsed_ms: start.elapsed().as_millis() as u64,
+            };
+            if !progress(&report) {
+                warn!("Temperature stabilization cancelled at {:.3} °C", temperature_c);
+                return Err(LabError::Aborted);
+            }
+            if stable >= hold_time {
+                info!("Temperature stable at {:.3} °C after {:?}", temperature_c, start.elapsed());
+                return Ok(temperature_c);
+            }
+            if start.elapsed() >= timeout {
+                error!("Temperature did not settle at {:.2} °C, last reading {:.3} °C", setpoint_c, temperature_c);
+                return Err(LabError::Timeout {
+                    device: "CLD1015".into(),
+                    command: "temperature stabilization".into(),
+                    timeout_ms: timeout.as_millis() as u64,
+                });
+            }
+            std::thread::sleep(TEMPERATURE_POLL_INTERVAL);
+        }
+    }
+
+    /// Diode temperature reported by the TEC controller, in °C.
+    pub fn get_temperature(&mut self) -> Result<f64> {
+        let response = self.query("MEASure:TEMPerature?")?;
+        response
+            .parse::<f64>()
+            .map_err(|_| LabError::ParseError(format!("Failed to parse temperature value: {}", response)))
+    }
+
+    /// Evaluates the safety rules for running at `current_amps` with the
+    /// output on, without changing anything on the instrument.
+    pub fn check_interlocks(&mut self, current_amps: f64) -> Result<()> {
+        self.safety.check_current(current_amps)?;
+        self.check_output_enable()
+    }
+
+    /// Same as `check_interlocks` for constant-power operation at `power_watts`.
+    pub fn check_power_interlocks(&mut self, power_watts: f64) -> Result<()> {
+        self.safety.check_power_setpoint(power_watts)?;
+        self.check_output_enable()
+    }
+
+    fn check_output_enable(&mut self) -> Result<()> {
+        let tec_on = self.get_tec_state()?;
+        let temperature_c = match self.safety.rules().temperature_window_c {
+            Some(_) => Some(self.get_temperature()?),
+            None => None,
+        };
+        self.safety.check_output_enable(tec_on, temperature_c)
+    }
+
+    pub fn get_laser_output(&mut self) -> Result<bool> {
         let response = self.query("OUTPut:STATe?")?;
         Ok(response.eq_ignore_ascii_case("ON") || response == "1")
     }
 
-    pub fn get_error(&mut self) -> visa_rs::Result<String> {
+    pub fn get_error(&mut self) -> Result<String> {
         let response = self.query("SYST:ERR?")?;
         info!("Queried CLD1015 error queue: {}", response);
         Ok(response)
     }
 
-    pub fn clear_error_queue(&mut self) -> visa_rs::Result<Vec<String>> {
+    /// Whether the error queue holds entries, from the error/event queue bit
+    /// of the status byte. Unlike `SYST:ERR?` this leaves the queue intact.
+    pub fn error_pending(&mut self) -> Result<bool> {
+        let response = self.query("*STB?")?;
+        let status = response
+            .parse::<u8>()
+            .map_err(|_| LabError::ParseError(format!("Failed to parse status byte: {}", response)))?;
+        Ok(status & STB_ERROR_QUEUE != 0)
+    }
+
+    pub fn clear_error_queue(&mut self) -> Result<Vec<String>> {
         let mut errors = Vec::new();
         loop {
             let response = self.query("SYST:ERR?")?;
             info!("Clearing error queue entry: {}", &response);
-            if response.starts_with("0") {
+            if LabError::from_error_queue(&response).is_none() {
                 break;
             }
             errors.push(response);
         }
         Ok(errors)
-    }    
+    }
+
+    /// Pops the oldest error queue entry and returns it as an
+    /// `InstrumentError`, if there is one.
+    pub fn check_error(&mut self) -> Result<()> {
+        let response = self.get_error()?;
+        match LabError::from_error_queue(&response) {
+            Some(err) => {
+                error!("CLD1015 reported: {}", err);
+                Err(err)
+            }
+            None => Ok(()),
+        }
+    }
     
 }
+
+impl LaserDriver for CLD1015 {
+    fn set_current(&mut self, current_amps: f64) -> Result<()> {
+        CLD1015::set_current(self, current_amps)
+    }
+
+    fn get_current(&mut self) -> Result<f64> {
+        CLD1015::get_current(self)
+    }
+
+    fn set_laser_output(&mut self, enabled: bool) -> Result<()> {
+        CLD1015::set_laser_output(self, enabled)
+    }
+
+    fn get_laser_output(&mut self) -> Result<bool> {
+        CLD1015::get_laser_output(self)
+    }
+
+    fn emergency_off(&mut self) -> Result<()> {
+        CLD1015::emergency_off(self)
+    }
+
+    fn measure_current(&mut self) -> Result<f64> {
+        CLD1015::measure_current(self)
+    }
+
+    fn get_forward_voltage(&mut self) -> Result<f64> {
+        CLD1015::get_forward_voltage(self)
+    }
+
+    fn set_operating_mode(&mut self, mode: OperatingMode) -> Result<()> {
+        CLD1015::set_operating_mode(self, mode)
+    }
+
+    fn set_power(&mut self, power_watts: f64) -> Result<()> {
+        CLD1015::set_power(self, power_watts)
+    }
+
+    fn measure_photodiode_current(&mut self) -> Result<f64> {
+        CLD1015::measure_photodiode_current(self)
+    }
+
+    fn measure_photodiode_power(&mut self) -> Result<f64> {
+        CLD1015::measure_photodiode_power(self)
+    }
+
+    fn enable_tec(&mut self) -> Result<()> {
+        CLD1015::enable_tec(self)
+    }
+
+    fn get_tec_state(&mut self) -> Result<bool> {
+        CLD1015::get_tec_state(self)
+    }
+
+    fn check_interlocks(&mut self, current_amps: f64) -> Result<()> {
+        CLD1015::check_interlocks(self, current_amps)
+    }
+
+    fn check_power_interlocks(&mut self, power_watts: f64) -> Result<()> {
+        CLD1015::check_power_interlocks(self, power_watts)
+    }
+
+    fn check_protection(&mut self) -> Result<()> {
+        CLD1015::check_protection(self)
+    }
+
+    fn wait_for_temperature_stable(
+        &mut self,
+        tolerance_c: f64,
+        hold_time: Duration,
+        timeout: Duration,
+        progress: &mut dyn FnMut(&TemperatureProgress) -> bool,
+    ) -> Result<f64> {
+        CLD1015::wait_for_temperature_stable(self, tolerance_c, hold_time, timeout, progress)
+    }
+
+    fn check_error(&mut self) -> Result<()> {
+        CLD1015::check_error(self)
+    }
+
+    fn telemetry(&mut self) -> LaserTelemetry {
+        monitor::laser_telemetry(self)
+    }
+}
+
+impl Connectable for CLD1015 {
+    fn connection_state(&self) -> ConnectionState {
+        if self.is_connected() {
+            ConnectionState::Connected
+        } else if self.lost {
+            ConnectionState::Lost
+        } else {
+            ConnectionState::Disconnected
+        }
+    }
+
+    fn ping(&mut self) -> Result<()> {
+        CLD1015::ping(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::MockTransport;
+    use crate::safety::SafetyRules;
+
+    const SET_CURRENT: &str = "SOURce:CURRent:LEVel:IMMediate:AMPLitude";
+
+    /// A controller behind `mock` that has answered the connect handshake
+    /// with its current limit matching `rules`.
+    fn connected(mock: &MockTransport, rules: SafetyRules) -> CLD1015 {
+        mock.reply("*IDN?", "Thorlabs,CLD1015,M00000000,1.0.0");
+        mock.reply("SOURce:CURRent:LIMit:AMPLitude?", &(rules.max_current_ma / 1000.0).to_string());
+        let mut cld = CLD1015::with_transport(TransportSpec::Mock(mock.clone()));
+        cld.set_safety(Arc::new(SafetySupervisor::new(rules)));
+        cld.connect().unwrap();
+        cld
+    }
+
+    /// The current setpoints written since command `from`, in order.
+    fn current_writes(mock: &MockTransport, from: usize) -> Vec<f64> {
+        mock.written()[from..]
+            .iter()
+            .filter_map(|command| command.strip_prefix(SET_CURRENT)?.trim().parse().ok())
+            .collect()
+    }
+
+    #[test]
+    fn current_is_ramped_within_the_slew_limit() {
+        let mock = MockTransport::new();
+        let rules = SafetyRules::default();
+        let mut cld = connected(&mock, rules.clone());
+        let start = mock.written().len();
+        mock.reply(&format!("{}?", SET_CURRENT), "0.02");
+
+        cld.set_current(0.125).unwrap();
+
+        let max_step = rules.max_slew_ma_per_ms.unwrap() * RAMP_STEP_INTERVAL.as_millis() as f64 / 1000.0;
+        let writes = current_writes(&mock, start);
+        assert!(writes.len() > 2);
+        assert_eq!(writes.last(), Some(&0.125));
+        let mut previous = 0.02;
+        for current in writes {
+            assert!((current - previous).abs() <= max_step + 1e-12, "{} A after {} A", current, previous);
+            previous = current;
+        }
+    }
+
+    #[test]
+    fn output_goes_off_after_the_current_is_ramped_down() {
+        let mock = MockTransport::new();
+        let mut cld = connected(&mock, SafetyRules::default());
+        let start = mock.written().len();
+        mock.reply(&format!("{}?", SET_CURRENT), "0.05");
+        mock.reply("SOURce:FUNCtion:MODE?", "CURR");
+
+        cld.set_laser_output(false).unwrap();
+
+        let written = mock.written();
+        assert_eq!(written.last().map(String::as_str), Some("OUTPut:STATe OFF"));
+        assert_eq!(written[start..].iter().filter(|c| c.starts_with("OUTPut:STATe")).count(), 1);
+        let writes = current_writes(&mock, start);
+        assert!(writes.len() > 1);
+        assert_eq!(writes.last(), Some(&0.0));
+    }
+
+    #[test]
+    fn emergency_off_does_not_ramp() {
+        let mock = MockTransport::new();
+        let mut cld = connected(&mock, SafetyRules::default());
+        mock.reply(&format!("{}?", SET_CURRENT), "0.02");
+        cld.set_current(0.1).unwrap();
+        let start = mock.written().len();
+
+        cld.emergency_off().unwrap();
+
+        assert_eq!(
+            mock.written()[start..],
+            ["OUTPut:STATe OFF".to_string(), format!("{} 0", SET_CURRENT)]
+        );
+    }
+
+    #[test]
+    fn setpoint_over_the_limit_writes_nothing() {
+        let mock = MockTransport::new();
+        let rules = SafetyRules {
+            max_current_ma: 100.0,
+            ..Default::default()
+        };
+        let mut cld = connected(&mock, rules);
+        let start = mock.written().len();
+
+        assert!(matches!(cld.set_current(0.2), Err(LabError::SafetyInterlock { .. })));
+        assert_eq!(mock.written().len(), start);
+    }
+
+    #[test]
+    fn limit_read_back_mismatch_refuses_the_connection() {
+        let mock = MockTransport::new();
+        mock.reply("*IDN?", "Thorlabs,CLD1015,M00000000,1.0.0");
+        mock.reply("SOURce:CURRent:LIMit:AMPLitude?", "1.2");
+        let mut cld = CLD1015::with_transport(TransportSpec::Mock(mock.clone()));
+
+        match cld.connect() {
+            Err(LabError::SafetyInterlock { rule, .. }) => assert_eq!(rule, "max_current"),
+            other => panic!("expected a safety interlock, got {:?}", other),
+        }
+        assert!(!cld.is_connected());
+        assert!(mock.written().contains(&"SOURce:CURRent:LIMit:AMPLitude 1.5".to_string()));
+    }
+
+    #[test]
+    fn limit_follows_the_safety_rules() {
+        let mock = MockTransport::new();
+        let mut cld = connected(&mock, SafetyRules::default());
+        cld.set_safety(Arc::new(SafetySupervisor::new(SafetyRules {
+            max_current_ma: 250.0,
+            ..Default::default()
+        })));
+        mock.reply("SOURce:CURRent:LIMit:AMPLitude?", "0.25");
+        cld.program_current_limit().unwrap();
+
+        mock.reply("SOURce:CURRent:LIMit:AMPLitude?", "1.5");
+        assert!(matches!(cld.program_current_limit(), Err(LabError::SafetyInterlock { .. })));
+    }
+
+    #[test]
+    fn protection_queries_are_decoded() {
+        let mock = MockTransport::new();
+        let mut cld = connected(&mock, SafetyRules::default());
+        mock.reply("OUTPut:PROTection:INTLock:TRIPped?", "0");
+        mock.reply("OUTPut:PROTection:OTEMp:TRIPped?", "ON");
+        mock.reply("OUTPut:PROTection:OCIRcuit:TRIPped?", "1");
+        assert_eq!(
+            cld.tripped_protections().unwrap(),
+            vec![Protection::OverTemperature, Protection::OpenCircuit]
+        );
+
+        mock.reply("OUTPut:PROTection:INTLock:TRIPped?", "1");
+        mock.reply("OUTPut:PROTection:OTEMp:TRIPped?", "OFF");
+        mock.reply("OUTPut:PROTection:OCIRcuit:TRIPped?", "0");
+        match cld.check_protection() {
+            Err(LabError::ProtectionTripped { protection, .. }) => assert_eq!(protection, "interlock"),
+            other => panic!("expected a tripped protection, got {:?}", other),
+        }
+
+        for query in ["INTLock", "OTEMp", "OCIRcuit"] {
+            mock.reply(&format!("OUTPut:PROTection:{}:TRIPped?", query), "0");
+        }
+        assert!(cld.tripped_protections().unwrap().is_empty());
+    }
+}
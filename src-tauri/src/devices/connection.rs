@@ -0,0 +1,142 @@
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter};
+use tracing::{error, info, warn};
+
+use crate::error::Result;
+
+/// How often the watchdog looks at a device.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How often an idle, connected device is pinged.
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Disconnected,
+    Connected,
+    /// The link failed while connected; the watchdog is trying to reconnect
+    Lost,
+}
+
+/// Payload of the `connection-state` event.
+#[derive(Serialize, Clone, Debug)]
+pub struct ConnectionEvent {
+    pub device: &'static str,
+    pub state: ConnectionState,
+    /// Reconnect attempts made since the link was lost
+    pub attempt: u32,
+    pub message: Option<String>,
+}
+
+/// Drivers whose link the watchdog can supervise.
+pub trait Connectable {
+    fn connection_state(&self) -> ConnectionState;
+
+    /// Talks to the instrument. A failed ping marks the link as lost.
+    fn ping(&mut self) -> Result<()>;
+}
+
+pub fn emit_connection_state(app: &AppHandle, event: ConnectionEvent) {
+    app.emit("connection-state", &event).unwrap_or_else(|e| {
+        error!("Failed to emit connection-state: {}", e);
+    });
+}
+
+/// Exponential delay between reconnect attempts.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff { initial, max, next: initial }
+    }
+
+    /// Delay before the next attempt; doubles on every call up to `max`.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+/// Starts a thread that pings `device` while it is idle and, once its link is
+/// lost, calls `reconnect` with exponential backoff until it succeeds or the
+/// device is disconnected on purpose. State changes are emitted as
+/// `connection-state` events.
+///
+/// The device is only ever `try_lock`ed, so a sweep holding it is never
+/// blocked; a failure during the sweep is picked up once it releases the lock.
+pub fn spawn_watchdog<D, F>(name: &'static str, device: Arc<Mutex<D>>, app: AppHandle, mut reconnect: F)
+where
+    D: Connectable + Send + 'static,
+    F: FnMut(&mut D) -> Result<String> + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(30));
+        let mut last_state = ConnectionState::Disconnected;
+        let mut last_ping = Instant::now();
+        let mut next_attempt = Instant::now();
+        let mut attempt = 0;
+
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let Ok(mut device) = device.try_lock() else {
+                continue; // busy, e.g. a sweep is running
+            };
+
+            if device.connection_state() == ConnectionState::Connected && last_ping.elapsed() >= PING_INTERVAL {
+                last_ping = Instant::now();
+                if let Err(e) = device.ping() {
+                    warn!("{} did not answer ping: {}", name, e);
+                }
+            }
+
+            let state = device.connection_state();
+            if state != last_state {
+                info!("{} connection state {:?} -> {:?}", name, last_state, state);
+                if state == ConnectionState::Lost {
+                    backoff.reset();
+                    attempt = 0;
+                    next_attempt = Instant::now() + backoff.next_delay();
+                }
+                emit_connection_state(&app, ConnectionEvent { device: name, state, attempt, message: None });
+                last_state = state;
+            }
+
+            if state == ConnectionState::Lost && Instant::now() >= next_attempt {
+                attempt += 1;
+                info!("Reconnecting {} (attempt {})", name, attempt);
+                match reconnect(&mut device) {
+                    Ok(id) => {
+                        info!("{} reconnected: {}", name, id);
+                        // The state change is reported on the next poll
+                    }
+                    Err(e) => {
+                        let delay = backoff.next_delay();
+                        warn!("Reconnecting {} failed: {}, retrying in {:?}", name, e, delay);
+                        next_attempt = Instant::now() + delay;
+                        emit_connection_state(
+                            &app,
+                            ConnectionEvent {
+                                device: name,
+                                state,
+                                attempt,
+                                message: Some(e.to_string()),
+                            },
+                        );
+                    }
+                }
+            }
+        }
+    });
+}
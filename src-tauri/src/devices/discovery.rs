@@ -0,0 +1,208 @@
+use serde::Serialize;
+use std::ffi::CString;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+use visa_rs::prelude::*;
+
+use super::transport::{ScpiTransport, StreamTransport, TransportSpec};
+use crate::config::{DiscoveryConfig, TransportConfig};
+use crate::error::{LabError, Result};
+
+/// Largest number of hosts probed for one subnet entry (a /22).
+const MAX_HOSTS_PER_SUBNET: u32 = 1022;
+/// TCP candidates probed in parallel.
+const PROBE_BATCH: usize = 64;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SuggestedDriver {
+    Cld1015,
+    Mpm210h,
+    Unknown,
+}
+
+/// An instrument that answered `*IDN?`.
+#[derive(Serialize, Clone, Debug)]
+pub struct DiscoveredInstrument {
+    /// How the instrument was reached, in `lab_config.json` form
+    pub transport: TransportConfig,
+    pub idn: String,
+    pub manufacturer: String,
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+    pub driver: SuggestedDriver,
+}
+
+impl DiscoveredInstrument {
+    /// Splits an `*IDN?` reply (`manufacturer,model,serial,firmware`).
+    fn from_idn(transport: TransportConfig, idn: &str) -> Self {
+        let mut fields = idn.split(',').map(|f| f.trim().to_string());
+        let manufacturer = fields.next().unwrap_or_default();
+        let model = fields.next().unwrap_or_default();
+        let serial = fields.next().unwrap_or_default();
+        let firmware = fields.next().unwrap_or_default();
+
+        let normalized = model.to_ascii_uppercase().replace('-', "");
+        let driver = if normalized.contains("CLD1015") {
+            SuggestedDriver::Cld1015
+        } else if normalized.contains("MPM210H") {
+            SuggestedDriver::Mpm210h
+        } else {
+            SuggestedDriver::Unknown
+        };
+
+        DiscoveredInstrument {
+            transport,
+            idn: idn.to_string(),
+            manufacturer,
+            model,
+            serial,
+            firmware,
+            driver,
+        }
+    }
+}
+
+/// Looks for instruments through the VISA resource manager and by probing
+/// the hosts and ports listed in `config`. Candidates that do not answer
+/// `*IDN?` are skipped, and so are the links in `held`, which belong to the
+/// application's own drivers.
+pub fn discover_instruments(config: &DiscoveryConfig, held: &[TransportSpec]) -> Result<Vec<DiscoveredInstrument>> {
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let mut candidates = tcp_candidates(config)?;
+    candidates.retain(|addr| {
+        let candidate = TransportConfig::Tcp { address: addr.to_string() };
+        !is_held(held, &candidate)
+    });
+    let mut found = Vec::new();
+
+    match visa_resources() {
+        Ok(resources) => {
+            for resource in resources {
+                let transport = TransportConfig::Visa { resource: resource.clone() };
+                if is_held(held, &transport) {
+                    continue;
+                }
+                let spec = TransportSpec::Visa { resource, timeout };
+                if let Some(instrument) = identify(transport, &spec.to_string(), spec.open()) {
+                    found.push(instrument);
+                }
+            }
+        }
+        // No VISA installation should not prevent the network scan
+        Err(e) => warn!("VISA discovery unavailable: {}", e),
+    }
+
+    info!("Probing {} TCP candidates", candidates.len());
+    for batch in candidates.chunks(PROBE_BATCH) {
+        let probes: Vec<_> = batch
+            .iter()
+            .map(|&addr| thread::spawn(move || probe_tcp(addr, timeout)))
+            .collect();
+        found.extend(probes.into_iter().filter_map(|probe| probe.join().ok().flatten()));
+    }
+
+    info!("Discovery found {} instruments", found.len());
+    Ok(found)
+}
+
+fn visa_resources() -> Result<Vec<String>> {
+    let rm = DefaultRM::new()?;
+    let expression = CString::new("?*INSTR").expect("static expression has no NUL");
+    let mut list = rm.find_res_list(&expression.into())?;
+    let mut resources = Vec::new();
+    while let Some(resource) = list.find_next()? {
+        resources.push(resource.to_string());
+    }
+    info!("VISA resources: {:?}", resources);
+    Ok(resources)
+}
+
+/// Whether `candidate` is a link one of the drivers already holds. Probing it
+/// would open a second session to an instrument that may accept only one, or
+/// interleave `*IDN?` with the driver's own commands.
+fn is_held(held: &[TransportSpec], candidate: &TransportConfig) -> bool {
+    held.iter().any(|spec| match (spec, candidate) {
+        (TransportSpec::Visa { resource, .. }, TransportConfig::Visa { resource: candidate }) => {
+            resource.eq_ignore_ascii_case(candidate)
+        }
+        (TransportSpec::Tcp { address, .. }, TransportConfig::Tcp { address: candidate }) => {
+            match (address.parse::<SocketAddr>(), candidate.parse::<SocketAddr>()) {
+                (Ok(address), Ok(candidate)) => address == candidate,
+                _ => address == candidate,
+            }
+        }
+        _ => false,
+    })
+}
+
+fn probe_tcp(addr: SocketAddr, timeout: Duration) -> Option<DiscoveredInstrument> {
+    // Unreachable hosts are dropped quietly; a host that accepts is queried
+    // over the same connection, as some instruments accept only one client
+    let stream = TcpStream::connect_timeout(&addr, timeout).ok()?;
+    let address = addr.to_string();
+    let link = stream
+        .set_nodelay(true)
+        .map(|_| Box::new(StreamTransport::new(address.clone(), stream, timeout)) as Box<dyn ScpiTransport>)
+        .map_err(LabError::from);
+    let name = format!("TCP {}", address);
+    identify(TransportConfig::Tcp { address }, &name, link)
+}
+
+fn identify(transport: TransportConfig, name: &str, link: Result<Box<dyn ScpiTransport>>) -> Option<DiscoveredInstrument> {
+    let idn = link.and_then(|mut link| link.query("*IDN?"));
+    match idn {
+        Ok(idn) if !idn.is_empty() => {
+            info!("{} identified as {}", name, idn);
+            Some(DiscoveredInstrument::from_idn(transport, &idn))
+        }
+        Ok(_) => None,
+        Err(e) => {
+            info!("{} did not identify: {}", name, e);
+            None
+        }
+    }
+}
+
+/// Expands the configured hosts (`a.b.c.d` or `a.b.c.d/prefix`) and ports.
+fn tcp_candidates(config: &DiscoveryConfig) -> Result<Vec<SocketAddr>> {
+    let mut candidates = Vec::new();
+    for entry in &config.hosts {
+        for host in expand_hosts(entry)? {
+            for &port in &config.ports {
+                candidates.push(SocketAddr::from((host, port)));
+            }
+        }
+    }
+    // Overlapping entries would report the same instrument twice
+    candidates.sort();
+    candidates.dedup();
+    Ok(candidates)
+}
+
+fn expand_hosts(entry: &str) -> Result<Vec<Ipv4Addr>> {
+    let invalid = |reason: &str| LabError::InvalidParameter(format!("Discovery host '{}': {}", entry, reason));
+    let (address, prefix) = match entry.split_once('/') {
+        Some((address, prefix)) => (address, prefix.parse::<u32>().map_err(|_| invalid("bad prefix"))?),
+        None => (entry, 32),
+    };
+    let address: Ipv4Addr = address.trim().parse().map_err(|_| invalid("not an IPv4 address"))?;
+    if prefix > 32 {
+        return Err(invalid("bad prefix"));
+    }
+    if prefix >= 31 {
+        return Ok(vec![address]);
+    }
+
+    let host_count = (1u64 << (32 - prefix)) - 2;
+    if host_count > MAX_HOSTS_PER_SUBNET as u64 {
+        return Err(invalid("subnet too large, use /22 or smaller"));
+    }
+    let host_count = host_count as u32;
+    // Skip the network and broadcast addresses
+    let network = u32::from(address) & (u32::MAX << (32 - prefix));
+    Ok((1..=host_count).map(|offset| Ipv4Addr::from(network + offset)).collect())
+}
@@ -1,5 +1,19 @@
 pub mod cld1015;
+pub mod connection;
+pub mod discovery;
 pub mod mpm210h;
+pub mod power;
+pub mod simulated_cld1015;
+pub mod simulated_mpm210h;
+pub mod traits;
+pub mod transport;
 
-pub use cld1015::CLD1015;
+pub use cld1015::{OperatingMode, PhotodiodePolarity, Protection, TemperatureProgress, CLD1015};
+pub use connection::{Connectable, ConnectionEvent, ConnectionState};
+pub use discovery::{discover_instruments, DiscoveredInstrument, SuggestedDriver};
 pub use mpm210h::MPM210H;
+pub use power::{PowerReading, PowerUnit, ReadingStatus};
+pub use simulated_cld1015::{LaserDiodeModel, SimulatedCLD1015};
+pub use simulated_mpm210h::{SimulatedMPM210H, SimulatedMPM210HConfig};
+pub use traits::{LaserDriver, PowerMeter};
+pub use transport::{MockTransport, ScpiTransport, StreamTransport, TransportSpec};
@@ -1,91 +1,155 @@
 #![allow(unused)]
 
-use std::io::{Read, Write};
-use std::net::{TcpStream, SocketAddr};
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
-use thiserror::Error;
 use tracing::{info, warn, error};
 
-#[derive(Error, Debug)]
-pub enum MPM210HError {
-    #[error("IO error: {0}")]
-    IoError(#[from] std::io::Error),
-    
-    #[error("Parse error: {0}")]
-    ParseError(String),
-    
-    #[error("Device not connected")]
-    NotConnected,
-}
-
-pub type Result<T> = std::result::Result<T, MPM210HError>;
+use super::connection::{Connectable, ConnectionState};
+use super::PowerMeter;
+use super::power::{parse_read_reply, PowerReading, PowerUnit};
+use super::transport::{ScpiTransport, TransportSpec, STB_ERROR_QUEUE};
+use crate::error::{LabError, Result};
+use crate::safety::SafetySupervisor;
 
 pub struct MPM210H {
-    connection: Option<TcpStream>,
-    address: String,
+    connection: Option<Box<dyn ScpiTransport>>,
+    spec: TransportSpec,
+    // Response timeouts by command header; others use the link default
+    command_timeouts: HashMap<String, Duration>,
+    // Unit the meter reports READ? values in, read back on connect
+    unit: PowerUnit,
+    // Set when the link failed while connected, cleared by connect/disconnect
+    lost: bool,
+    safety: Arc<SafetySupervisor>,
 }
 
 impl MPM210H {
     pub fn new(ip_address: &str, port: u16) -> Self {
-        let address = format!("{}:{}", ip_address, port);
-        info!("Initializing MPM210H with address: {}", address);
+        Self::with_transport(TransportSpec::Tcp {
+            address: format!("{}:{}", ip_address, port),
+            timeout: Duration::from_secs(2),
+        })
+    }
+
+    /// Creates a driver that reaches the power meter over an arbitrary link.
+    pub fn with_transport(spec: TransportSpec) -> Self {
+        info!("Initializing MPM210H over {}", spec);
+        let mut command_timeouts = HashMap::new();
+        // Reading every port of a module takes noticeably longer than other queries
+        command_timeouts.insert("READ?".to_string(), Duration::from_secs(5));
         MPM210H {
             connection: None,
-            address,
+            spec,
+            command_timeouts,
+            unit: PowerUnit::Dbm,
+            lost: false,
+            safety: Arc::new(SafetySupervisor::default()),
         }
     }
 
+    /// Shares the supervisor that checks wavelengths and read-back power.
+    pub fn set_safety(&mut self, safety: Arc<SafetySupervisor>) {
+        self.safety = safety;
+    }
+
+    /// Overrides the response timeout for queries starting with `header`
+    /// (e.g. `"READ?"`).
+    pub fn set_command_timeout(&mut self, header: &str, timeout: Duration) {
+        self.command_timeouts.insert(header.to_ascii_uppercase(), timeout);
+    }
+
+    fn timeout_for(&self, command: &str) -> Option<Duration> {
+        let header = command.split_whitespace().next().unwrap_or_default();
+        self.command_timeouts.get(&header.to_ascii_uppercase()).copied()
+    }
+
     pub fn connect(&mut self) -> Result<String> {
-        info!("Attempting to connect to MPM210H at {}", self.address);
-        let socket_addr: SocketAddr = self.address.parse()
-            .map_err(|e: std::net::AddrParseError| MPM210HError::ParseError(e.to_string()))?;
-        
-        let stream = TcpStream::connect(socket_addr)?;
-        stream.set_read_timeout(Some(Duration::from_secs(2)))?;
-        
-        self.connection = Some(stream);
+        info!("Attempting to connect to MPM210H over {}", self.spec);
+        self.connection = Some(self.spec.open()?);
         
         // Return the device identification
-        let id = self.query("*IDN?")?;
+        let id = self.query("*IDN?").inspect_err(|_| self.connection = None)?;
+        // READ? replies come in the unit last selected on the meter, which may
+        // have been changed on the front panel or by another program
+        let unit = self.get_unit().inspect_err(|_| self.connection = None)?;
+        info!("MPM210H reports power in {:?}", unit);
+        self.lost = false;
         info!("MPM210H connected successfully. IDN: {}", id);
         Ok(id)
     }
-    
+
+    pub fn disconnect(&mut self) {
+        if self.connection.take().is_some() {
+            info!("Disconnected MPM210H");
+        }
+        self.lost = false;
+    }
 
     pub fn is_connected(&self) -> bool {
         self.connection.is_some()
     }
 
-    pub fn send_command(&mut self, command: &str) -> Result<()> {
-        if let Some(stream) = &mut self.connection {
-            let cmd = format!("{}\n", command);
-            info!("Sending command to MPM210H: {}", command);
-            stream.write_all(cmd.as_bytes())?;
-            stream.flush()?;
-            Ok(())
-        } else {
-            error!("Attempted to send command but MPM210H is not connected");
-            Err(MPM210HError::NotConnected)
+    /// The link the driver connects over.
+    pub fn transport_spec(&self) -> &TransportSpec {
+        &self.spec
+    }
+
+    /// Queries `*IDN?` to check the instrument still answers. A failed ping
+    /// marks the link as lost.
+    pub fn ping(&mut self) -> Result<()> {
+        match self.query("*IDN?") {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                if self.connection.take().is_some() {
+                    warn!("MPM210H lost: {}", e);
+                    self.lost = true;
+                }
+                Err(e)
+            }
         }
     }
 
-    pub fn read_response(&mut self) -> Result<String> {
-        if let Some(stream) = &mut self.connection {
-            let mut buf = [0_u8; 1024];
-            let n = stream.read(&mut buf)?;
-            let response = String::from_utf8_lossy(&buf[..n]).trim().to_string();
-            info!("Received response from MPM210H: {}", response);
-            Ok(response)
-        } else {
-            error!("Attempted to read from MPM210H but device is not connected");
-            Err(MPM210HError::NotConnected)
+    /// Drops the link after a transport failure so it can be reconnected.
+    fn check_link<T>(&mut self, result: Result<T>) -> Result<T> {
+        if let Err(LabError::Io(e)) = &result {
+            error!("MPM210H link failed: {}", e);
+            self.connection = None;
+            self.lost = true;
         }
+        result
+    }
+
+    fn connection(&mut self) -> Result<&mut Box<dyn ScpiTransport>> {
+        self.connection.as_mut().ok_or_else(|| {
+            error!("Attempted to talk to MPM210H but device is not connected");
+            LabError::NotConnected {
+                device: "MPM210H".into(),
+            }
+        })
+    }
+
+    pub fn send_command(&mut self, command: &str) -> Result<()> {
+        let result = self.connection()?.write_line(command);
+        self.check_link(result)
+    }
+
+    pub fn read_response(&mut self) -> Result<String> {
+        let result = self.connection()?.read_line();
+        self.check_link(result)
+    }
+
+    fn read_response_timeout(&mut self, timeout: Duration) -> Result<String> {
+        let result = self.connection()?.read_line_timeout(timeout);
+        self.check_link(result)
     }
 
     pub fn query(&mut self, command: &str) -> Result<String> {
-        self.send_command(command)?;
-        std::thread::sleep(Duration::from_millis(10)); // Add a small delay
-        self.read_response()
+        let timeout = self.timeout_for(command);
+        let connection = self.connection()?;
+        let timeout = timeout.unwrap_or_else(|| connection.timeout());
+        let result = connection.query_timeout(command, timeout);
+        self.check_link(result)
     }
 
     pub fn get_recognized_modules(&mut self) -> Result<String> {
@@ -95,7 +159,9 @@ impl MPM210H {
     pub fn perform_zeroing(&mut self) -> Result<()> {
         info!("Performing zeroing operation to remove electrical offsets");
         if !self.is_connected() {
-            return Err(MPM210HError::NotConnected);
+            return Err(LabError::NotConnected {
+                device: "MPM210H".into(),
+            });
         }
         self.send_command("ZERO")?;
         info!("Zeroing command sent successfully");
@@ -103,9 +169,75 @@ impl MPM210H {
     }
     
 
-    pub fn read_power(&mut self, module: u8) -> Result<String> {
+    /// Reads every port of `module`.
+    pub fn read_power(&mut self, module: u8) -> Result<Vec<PowerReading>> {
         info!("Reading power from module {}", module);
-        self.query(&format!("READ? {}", module))
+        let reply = self.query(&format!("READ? {}", module))?;
+        let readings = parse_read_reply(&reply, module, self.unit)?;
+        self.safety.check_optical_power(&readings)?;
+        Ok(readings)
+    }
+
+    /// Reads every port of each module in `modules` in a single round trip:
+    /// all `READ?` queries are written before the first reply is read.
+    ///
+    /// Every reply is read before any is parsed, so a malformed reply does
+    /// not leave the ones after it queued as answers to later queries. Replies
+    /// still outstanding after a timeout are discarded by the transport.
+    pub fn read_modules(&mut self, modules: &[u8]) -> Result<Vec<PowerReading>> {
+        info!("Reading power from modules {:?}", modules);
+        let timeout = match self.timeout_for("READ?") {
+            Some(timeout) => timeout,
+            None => self.connection()?.timeout(),
+        };
+
+        for module in modules {
+            self.send_command(&format!("READ? {}", module))?;
+        }
+        let mut replies = Vec::with_capacity(modules.len());
+        for &module in modules {
+            let reply = self.read_response_timeout(timeout).map_err(|e| match e {
+                LabError::Timeout { device, timeout_ms, .. } => LabError::Timeout {
+                    device,
+                    command: format!("READ? {}", module),
+                    timeout_ms,
+                },
+                other => other,
+            })?;
+            replies.push((module, reply));
+        }
+        let mut readings = Vec::new();
+        for (module, reply) in replies {
+            readings.extend(parse_read_reply(&reply, module, self.unit)?);
+        }
+        self.safety.check_optical_power(&readings)?;
+        Ok(readings)
+    }
+
+    /// Selects the unit of `READ?` values. The meter only supports dBm and mW.
+    pub fn set_unit(&mut self, unit: PowerUnit) -> Result<()> {
+        let code = match unit {
+            PowerUnit::Dbm => 0,
+            PowerUnit::MilliWatt => 1,
+            PowerUnit::Watt => {
+                return Err(LabError::InvalidParameter("MPM210H cannot report power in W".into()));
+            }
+        };
+        info!("Setting MPM210H power unit to {:?}", unit);
+        self.send_command(&format!("UNIT {}", code))?;
+        self.unit = unit;
+        Ok(())
+    }
+
+    pub fn get_unit(&mut self) -> Result<PowerUnit> {
+        let response = self.query("UNIT?")?;
+        let unit = match response.trim() {
+            "0" => PowerUnit::Dbm,
+            "1" => PowerUnit::MilliWatt,
+            other => return Err(LabError::ParseError(format!("Unknown power unit: {}", other))),
+        };
+        self.unit = unit;
+        Ok(unit)
     }
 
     pub fn get_wavelength(&mut self) -> Result<String> {
@@ -113,6 +245,7 @@ impl MPM210H {
     }
 
     pub fn set_wavelength(&mut self, wavelength: u32) -> Result<()> {
+        self.safety.check_wavelength(wavelength as f64)?;
         info!("Setting MPM210H wavelength {}", wavelength);
         self.send_command(&format!("WAV {}", wavelength))
     }
@@ -123,18 +256,179 @@ impl MPM210H {
         Ok(response)
     }
 
+    /// Whether the error queue holds entries, read from the status byte so
+    /// the entries stay queued for `get_error` or the next command check.
+    pub fn error_pending(&mut self) -> Result<bool> {
+        let response = self.query("*STB?")?;
+        let status = response
+            .parse::<u8>()
+            .map_err(|_| LabError::ParseError(format!("Failed to parse MPM210H status byte: {}", response)))?;
+        Ok(status & STB_ERROR_QUEUE != 0)
+    }
+
     // Clear all errors (only if needed)
     pub fn clear_error_queue(&mut self) -> Result<Vec<String>> {
         let mut errors = Vec::new();
         loop {
             let response = self.query("ERR?")?;
             info!("Clearing error queue entry from MPM210H: {}", response);
-            if response.trim().starts_with("0") || response.to_lowercase().contains("no error") {
+            if LabError::from_error_queue(&response).is_none() {
                 break;
             }
             errors.push(response);
         }
         Ok(errors)
-    }    
-    
+    }
+
+    /// Pops the oldest error queue entry and returns it as an
+    /// `InstrumentError`, if there is one.
+    pub fn check_error(&mut self) -> Result<()> {
+        let response = self.get_error()?;
+        match LabError::from_error_queue(&response) {
+            Some(err) => {
+                error!("MPM210H reported: {}", err);
+                Err(err)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl PowerMeter for MPM210H {
+    fn read_power(&mut self, module: u8) -> Result<Vec<PowerReading>> {
+        MPM210H::read_power(self, module)
+    }
+
+    fn read_modules(&mut self, modules: &[u8]) -> Result<Vec<PowerReading>> {
+        MPM210H::read_modules(self, modules)
+    }
+
+    fn set_wavelength(&mut self, wavelength: u32) -> Result<()> {
+        MPM210H::set_wavelength(self, wavelength)
+    }
+
+    fn perform_zeroing(&mut self) -> Result<()> {
+        MPM210H::perform_zeroing(self)
+    }
+
+    fn check_error(&mut self) -> Result<()> {
+        MPM210H::check_error(self)
+    }
+}
+
+impl Connectable for MPM210H {
+    fn connection_state(&self) -> ConnectionState {
+        if self.is_connected() {
+            ConnectionState::Connected
+        } else if self.lost {
+            ConnectionState::Lost
+        } else {
+            ConnectionState::Disconnected
+        }
+    }
+
+    fn ping(&mut self) -> Result<()> {
+        MPM210H::ping(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{MockTransport, ReadingStatus};
+
+    /// A meter behind `mock` that has answered the connect handshake.
+    fn connected(mock: &MockTransport) -> MPM210H {
+        mock.reply("*IDN?", "santec,MPM-210H,00000000,Ver1.00");
+        mock.reply("UNIT?", "0");
+        let mut mpm = MPM210H::with_transport(TransportSpec::Mock(mock.clone()));
+        mpm.set_command_timeout("READ?", mock.timeout());
+        mpm.connect().unwrap();
+        mpm
+    }
+
+    #[test]
+    fn connect_reads_power_unit() {
+        let mock = MockTransport::new();
+        mock.reply("*IDN?", "santec,MPM-210H,00000000,Ver1.00");
+        mock.reply("UNIT?", "1");
+        mock.reply("READ? 1", "1.000000E+00,1.0E-10");
+        let mut mpm = MPM210H::with_transport(TransportSpec::Mock(mock.clone()));
+        mpm.connect().unwrap();
+
+        let readings = mpm.read_power(1).unwrap();
+        assert_eq!(readings[0].unit, PowerUnit::MilliWatt);
+        assert_eq!(readings[0].dbm(), Some(0.0));
+        assert_eq!(readings[1].status, ReadingStatus::Underrange);
+        assert_eq!(mock.written(), vec!["*IDN?", "UNIT?", "READ? 1"]);
+    }
+
+    #[test]
+    fn frames_split_and_merged_replies() {
+        let mock = MockTransport::new();
+        let mut mpm = connected(&mock);
+        // One reply split over three reads, ending in CR LF
+        mock.reply_chunks("READ? 1", &["-10.0", "00,-20.", "000\r\n"]);
+        // The replies to both queries arriving in one read
+        mock.reply_chunks("READ? 2", &["-1.0,-2.0\n-3.0,-4.0\n"]);
+
+        let readings = mpm.read_power(1).unwrap();
+        assert_eq!(readings.iter().map(|r| r.value).collect::<Vec<_>>(), vec![-10.0, -20.0]);
+        let readings = mpm.read_modules(&[2, 3]).unwrap();
+        assert_eq!(readings.iter().map(|r| r.value).collect::<Vec<_>>(), vec![-1.0, -2.0, -3.0, -4.0]);
+        assert_eq!(readings[3].module, 3);
+    }
+
+    #[test]
+    fn timeout_names_the_command() {
+        let mock = MockTransport::with_timeout(Duration::from_millis(50));
+        let mut mpm = connected(&mock);
+
+        match mpm.read_power(1) {
+            Err(LabError::Timeout { command, timeout_ms, .. }) => {
+                assert_eq!(command, "READ? 1");
+                assert_eq!(timeout_ms, 50);
+            }
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        // A timeout is not a broken link
+        assert!(mpm.is_connected());
+    }
+
+    #[test]
+    fn late_reply_does_not_answer_the_next_query() {
+        let mock = MockTransport::with_timeout(Duration::from_millis(50));
+        let mut mpm = connected(&mock);
+        mock.reply_late("READ? 1", "-99.0,-99.0", Duration::from_millis(80));
+        mock.reply("WAV?", "1550.000");
+        mock.reply("READ? 1", "-10.0,-20.0");
+
+        assert!(matches!(mpm.read_power(1), Err(LabError::Timeout { .. })));
+        assert_eq!(mpm.get_wavelength().unwrap(), "1550.000");
+        let readings = mpm.read_power(1).unwrap();
+        assert_eq!(readings[0].value, -10.0);
+    }
+
+    #[test]
+    fn bad_reply_leaves_no_replies_queued() {
+        let mock = MockTransport::new();
+        let mut mpm = connected(&mock);
+        mock.reply("READ? 1", "not a number");
+        mock.reply("READ? 2", "-1.0,-2.0");
+        mock.reply("WAV?", "1550.000");
+
+        assert!(matches!(mpm.read_modules(&[1, 2]), Err(LabError::ParseError(_))));
+        assert_eq!(mpm.get_wavelength().unwrap(), "1550.000");
+    }
+
+    #[test]
+    fn queued_instrument_error_is_reported() {
+        let mock = MockTransport::new();
+        let mut mpm = connected(&mock);
+        mock.reply("ERR?", "-222,\"Data out of range\"");
+        mock.reply("ERR?", "0,\"No error\"");
+
+        assert!(matches!(mpm.check_error(), Err(LabError::InstrumentError { code: -222, .. })));
+        assert!(mpm.check_error().is_ok());
+    }
 }
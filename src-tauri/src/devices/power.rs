@@ -0,0 +1,181 @@
+use serde::Serialize;
+use tracing::warn;
+
+use crate::error::{LabError, Result};
+
+// `READ? <module>` answers with one value per port, comma separated, in the
+// unit selected with `UNIT` (0: dBm as `-12.345`, 1: mW as `5.623413E-02`).
+// A port whose input is outside its measurement range does not report an
+// error but a fixed marker value, far outside the range any module can
+// measure. The limits below sit between the measurable range and those
+// markers and apply to both units after conversion to dBm; a linear reading
+// of zero or less is also counted as underrange.
+
+/// Readings at or above this level are the meter's overrange marker, in dBm.
+const OVERRANGE_DBM: f64 = 90.0;
+/// Readings at or below this level are the meter's underrange marker, in dBm.
+const UNDERRANGE_DBM: f64 = -100.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PowerUnit {
+    #[serde(rename = "dBm")]
+    Dbm,
+    #[serde(rename = "mW")]
+    MilliWatt,
+    #[serde(rename = "W")]
+    Watt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadingStatus {
+    Ok,
+    Overrange,
+    Underrange,
+}
+
+/// One optical power value from a power meter channel.
+#[derive(Debug, Clone, Serialize)]
+pub struct PowerReading {
+    pub module: u8,
+    pub port: u8,
+    pub value: f64,
+    pub unit: PowerUnit,
+    pub status: ReadingStatus,
+}
+
+impl PowerReading {
+    pub fn new(module: u8, port: u8, value: f64, unit: PowerUnit) -> Self {
+        let dbm = to_dbm(value, unit);
+        let status = if dbm >= OVERRANGE_DBM {
+            ReadingStatus::Overrange
+        } else if dbm <= UNDERRANGE_DBM || dbm.is_nan() {
+            ReadingStatus::Underrange
+        } else {
+            ReadingStatus::Ok
+        };
+        if status != ReadingStatus::Ok {
+            warn!("Module {} port {} reading {} {:?} is {:?}", module, port, value, unit, status);
+        }
+        PowerReading { module, port, value, unit, status }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.status == ReadingStatus::Ok
+    }
+
+    /// Power in dBm, or `None` when the reading is out of range.
+    pub fn dbm(&self) -> Option<f64> {
+        self.is_valid().then(|| to_dbm(self.value, self.unit))
+    }
+
+    /// Power in mW, or `None` when the reading is out of range.
+    pub fn milliwatts(&self) -> Option<f64> {
+        self.is_valid().then(|| to_milliwatts(self.value, self.unit))
+    }
+}
+
+fn to_milliwatts(value: f64, unit: PowerUnit) -> f64 {
+    match unit {
+        PowerUnit::Dbm => 10f64.powf(value / 10.0),
+        PowerUnit::MilliWatt => value,
+        PowerUnit::Watt => value * 1000.0,
+    }
+}
+
+fn to_dbm(value: f64, unit: PowerUnit) -> f64 {
+    match unit {
+        PowerUnit::Dbm => value,
+        // Non-positive linear power has no dBm value; treat it as underrange
+        _ if value <= 0.0 => f64::NEG_INFINITY,
+        _ => 10.0 * to_milliwatts(value, unit).log10(),
+    }
+}
+
+/// Parses a `READ?` reply: one comma-separated value per port of `module`,
+/// starting at port 1.
+pub fn parse_read_reply(reply: &str, module: u8, unit: PowerUnit) -> Result<Vec<PowerReading>> {
+    let reply = reply.trim();
+    if reply.is_empty() {
+        return Err(LabError::ParseError(format!("Empty power reading for module {}", module)));
+    }
+    reply
+        .split(',')
+        .enumerate()
+        .map(|(i, field)| {
+            let value = field.trim().parse::<f64>().map_err(|_| {
+                LabError::ParseError(format!("Invalid power value '{}' for module {}", field.trim(), module))
+            })?;
+            Ok(PowerReading::new(module, i as u8 + 1, value, unit))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statuses(readings: &[PowerReading]) -> Vec<ReadingStatus> {
+        readings.iter().map(|r| r.status).collect()
+    }
+
+    #[test]
+    fn parses_dbm_reply() {
+        let readings = parse_read_reply("-12.345, -3.000,0.500\r\n", 2, PowerUnit::Dbm).unwrap();
+        assert_eq!(readings.len(), 3);
+        assert_eq!((readings[0].module, readings[0].port), (2, 1));
+        assert_eq!((readings[2].module, readings[2].port), (2, 3));
+        assert_eq!(statuses(&readings), vec![ReadingStatus::Ok; 3]);
+        assert_eq!(readings[1].dbm(), Some(-3.0));
+        assert!((readings[1].milliwatts().unwrap() - 0.501187).abs() < 1e-6);
+    }
+
+    #[test]
+    fn parses_milliwatt_reply() {
+        let readings = parse_read_reply("1.000000E+00,5.623413E-02", 1, PowerUnit::MilliWatt).unwrap();
+        assert_eq!(statuses(&readings), vec![ReadingStatus::Ok; 2]);
+        assert_eq!(readings[0].milliwatts(), Some(1.0));
+        assert!((readings[1].dbm().unwrap() + 12.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn flags_out_of_range_dbm() {
+        let readings = parse_read_reply("-20.0,90.0,99.999,-100.0,-150.0", 1, PowerUnit::Dbm).unwrap();
+        assert_eq!(
+            statuses(&readings),
+            vec![
+                ReadingStatus::Ok,
+                ReadingStatus::Overrange,
+                ReadingStatus::Overrange,
+                ReadingStatus::Underrange,
+                ReadingStatus::Underrange,
+            ]
+        );
+        assert_eq!(readings[1].dbm(), None);
+        assert_eq!(readings[3].milliwatts(), None);
+    }
+
+    #[test]
+    fn flags_out_of_range_milliwatts() {
+        // 1E+09 mW is 90 dBm, 1E-10 mW is -100 dBm
+        let readings = parse_read_reply("1.0E-02,1.0E+09,1.0E-10,0,-1.0E-03", 1, PowerUnit::MilliWatt).unwrap();
+        assert_eq!(
+            statuses(&readings),
+            vec![
+                ReadingStatus::Ok,
+                ReadingStatus::Overrange,
+                ReadingStatus::Underrange,
+                ReadingStatus::Underrange,
+                ReadingStatus::Underrange,
+            ]
+        );
+        assert_eq!(readings[0].dbm(), Some(-20.0));
+        assert_eq!(readings[1].milliwatts(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_reply() {
+        assert!(matches!(parse_read_reply("", 1, PowerUnit::Dbm), Err(LabError::ParseError(_))));
+        assert!(matches!(parse_read_reply("-3.0,abc", 1, PowerUnit::Dbm), Err(LabError::ParseError(_))));
+    }
+}
@@ -0,0 +1,540 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use super::cld1015::{OperatingMode, PhotodiodePolarity, Protection};
+use super::transport::STB_ERROR_QUEUE;
+
+/// Electrical/optical model of the simulated laser diode.
+#[derive(Debug, Clone)]
+pub struct LaserDiodeModel {
+    pub threshold_current_amps: f64,
+    pub slope_efficiency_w_per_a: f64,
+    /// Diode turn-on voltage and series resistance, V = V_on + I R_s
+    pub turn_on_voltage_v: f64,
+    pub series_resistance_ohm: f64,
+    /// Monitor photodiode current per watt of optical output
+    pub monitor_coupling_a_per_w: f64,
+    pub tec_warmup: Duration,
+    /// Temperature the diode settles to with the TEC off
+    pub ambient_c: f64,
+    /// Time constant of the exponential approach to the TEC setpoint
+    pub tec_time_constant: Duration,
+}
+
+impl Default for LaserDiodeModel {
+    fn default() -> Self {
+        LaserDiodeModel {
+            threshold_current_amps: 0.030,
+            slope_efficiency_w_per_a: 0.8,
+            turn_on_voltage_v: 1.3,
+            series_resistance_ohm: 1.5,
+            monitor_coupling_a_per_w: 0.05,
+            tec_warmup: Duration::from_secs(2),
+            ambient_c: 25.0,
+            tec_time_constant: Duration::from_secs(1),
+        }
+    }
+}
+
+struct SimState {
+    model: LaserDiodeModel,
+    mode: OperatingMode,
+    current_amps: f64,
+    // Regulated monitor photodiode power in constant-power mode
+    power_setpoint_w: f64,
+    current_limit_amps: f64,
+    laser_on: bool,
+    tripped: Vec<Protection>,
+    tec_on_since: Option<Instant>,
+    temperature_c: f64,
+    tec_setpoint_c: f64,
+    tec_current_limit_amps: f64,
+    // Responsivity programmed into the controller, not the model's coupling
+    pd_responsivity_a_per_w: f64,
+    pd_polarity: PhotodiodePolarity,
+    // When `temperature_c` was last brought up to date
+    temperature_at: Instant,
+    errors: VecDeque<String>,
+    input: Vec<u8>,
+    output: VecDeque<u8>,
+}
+
+/// In-process stand-in for the CLD1015 that answers the SCPI subset used by
+/// the driver. Cloning yields another handle to the same simulated instrument.
+#[derive(Clone)]
+pub struct SimulatedCLD1015 {
+    state: Arc<Mutex<SimState>>,
+}
+
+impl SimulatedCLD1015 {
+    pub fn new(model: LaserDiodeModel) -> Self {
+        info!("Creating simulated CLD1015: {:?}", model);
+        let ambient_c = model.ambient_c;
+        SimulatedCLD1015 {
+            state: Arc::new(Mutex::new(SimState {
+                model,
+                mode: OperatingMode::Current,
+                current_amps: 0.0,
+                power_setpoint_w: 0.0,
+                current_limit_amps: 1.5,
+                laser_on: false,
+                tripped: Vec::new(),
+                tec_on_since: None,
+                temperature_c: ambient_c,
+                tec_setpoint_c: 25.0,
+                tec_current_limit_amps: 2.0,
+                pd_responsivity_a_per_w: 1.0,
+                pd_polarity: PhotodiodePolarity::CathodeGrounded,
+                temperature_at: Instant::now(),
+                errors: VecDeque::new(),
+                input: Vec::new(),
+                output: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// Optical output power of the modelled diode in watts.
+    pub fn optical_power_w(&self) -> f64 {
+        self.state.lock().unwrap().optical_power_w()
+    }
+
+    /// Trips or clears a hardware protection. Tripping switches the laser
+    /// output off, as on the real controller.
+    pub fn set_protection(&self, protection: Protection, tripped: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.tripped.retain(|&p| p != protection);
+        if tripped {
+            warn!("Simulated CLD1015 protection tripped: {}", protection);
+            state.tripped.push(protection);
+            state.laser_on = false;
+        }
+    }
+}
+
+impl SimState {
+    fn tec_stable(&self) -> bool {
+        self.tec_on_since
+            .map(|since| since.elapsed() >= self.model.tec_warmup)
+            .unwrap_or(false)
+    }
+
+    /// Moves the diode temperature towards the TEC setpoint (or ambient with
+    /// the TEC off) for the time elapsed since the last update.
+    fn update_temperature(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.temperature_at).as_secs_f64();
+        self.temperature_at = now;
+        let target = if self.tec_on_since.is_some() {
+            self.tec_setpoint_c
+        } else {
+            self.model.ambient_c
+        };
+        let tau = self.model.tec_time_constant.as_secs_f64().max(1e-3);
+        self.temperature_c += (target - self.temperature_c) * (1.0 - (-elapsed / tau).exp());
+    }
+
+    /// Current through the diode; nothing flows with the output off. In
+    /// constant-power mode it is the current at which the photodiode reads
+    /// the setpoint, up to the current limit.
+    fn diode_current_amps(&self) -> f64 {
+        if !self.laser_on {
+            return 0.0;
+        }
+        match self.mode {
+            OperatingMode::Current => self.current_amps,
+            OperatingMode::Power => {
+                let photodiode_amps = self.power_setpoint_w * self.pd_responsivity_a_per_w;
+                let optical_w = photodiode_amps / self.model.monitor_coupling_a_per_w;
+                if optical_w <= 0.0 {
+                    return 0.0;
+                }
+                let amps = self.model.threshold_current_amps + optical_w / self.model.slope_efficiency_w_per_a;
+                amps.min(self.current_limit_amps)
+            }
+        }
+    }
+
+    fn optical_power_w(&self) -> f64 {
+        let above_threshold = self.diode_current_amps() - self.model.threshold_current_amps;
+        above_threshold.max(0.0) * self.model.slope_efficiency_w_per_a
+    }
+
+    fn photodiode_current_amps(&self) -> f64 {
+        self.optical_power_w() * self.model.monitor_coupling_a_per_w
+    }
+
+    fn forward_voltage_v(&self) -> f64 {
+        let current_amps = self.diode_current_amps();
+        if current_amps > 0.0 {
+            self.model.turn_on_voltage_v + current_amps * self.model.series_resistance_ohm
+        } else {
+            0.0
+        }
+    }
+
+    /// Resistance of the 10 kΩ NTC thermistor (B = 3988 K) at the diode temperature.
+    fn thermistor_ohms(&self) -> f64 {
+        let kelvin = self.temperature_c + 273.15;
+        10_000.0 * (3988.0 * (1.0 / kelvin - 1.0 / 298.15)).exp()
+    }
+
+    fn push_error(&mut self, code: i32, message: &str) {
+        warn!("Simulated CLD1015 error {}: {}", code, message);
+        self.errors.push_back(format!("{},\"{}\"", code, message));
+    }
+
+    fn respond_tripped(&mut self, protection: Protection) {
+        let tripped = self.tripped.contains(&protection);
+        self.respond(if tripped { "1" } else { "0" }.into());
+    }
+
+    fn respond(&mut self, response: String) {
+        self.output.extend(response.bytes());
+        self.output.push_back(b'\n');
+    }
+
+    fn handle(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() {
+            return;
+        }
+        let (header, argument) = match line.split_once(char::is_whitespace) {
+            Some((header, argument)) => (header, Some(argument.trim())),
+            None => (line, None),
+        };
+
+        self.update_temperature();
+        match (header.to_ascii_uppercase().as_str(), argument) {
+            ("*IDN?", None) => {
+                self.respond("Thorlabs,CLD1015,SIM000000,1.0.0-sim".into());
+            }
+            ("*STB?", None) => {
+                let status = if self.errors.is_empty() { 0 } else { STB_ERROR_QUEUE };
+                self.respond(status.to_string());
+            }
+            ("SYST:ERR?", None) => {
+                let error = self.errors.pop_front().unwrap_or_else(|| "0,\"No error\"".into());
+                self.respond(error);
+            }
+            ("OUTPUT2:STATE?", None) => {
+                let on = self.tec_on_since.is_some();
+                self.respond(if on { "1" } else { "0" }.into());
+            }
+            ("OUTPUT2:STATE", Some(argument)) => match parse_bool(argument) {
+                Some(true) => {
+                    if self.tec_on_since.is_none() {
+                        self.tec_on_since = Some(Instant::now());
+                    }
+                }
+                Some(false) => {
+                    self.tec_on_since = None;
+                    // The real controller drops the laser output with the TEC
+                    self.laser_on = false;
+                }
+                None => self.push_error(-224, "Illegal parameter value"),
+            },
+            ("OUTPUT:STATE?", None) | ("OUTPUT1:STATE?", None) => {
+                self.respond(if self.laser_on { "1" } else { "0" }.into());
+            }
+            ("OUTPUT:STATE", Some(argument)) | ("OUTPUT1:STATE", Some(argument)) => {
+                match parse_bool(argument) {
+                    Some(true) if !self.tec_stable() => {
+                        self.push_error(-221, "Settings conflict;TEC not stabilized");
+                    }
+                    Some(true) if !self.tripped.is_empty() => {
+                        self.push_error(-221, "Settings conflict;protection tripped");
+                    }
+                    Some(enabled) => self.laser_on = enabled,
+                    None => self.push_error(-224, "Illegal parameter value"),
+                }
+            }
+            ("SOURCE:CURRENT:LEVEL:IMMEDIATE:AMPLITUDE?", None) => {
+                self.respond(format!("{:.6E}", self.current_amps));
+            }
+            ("SOURCE:CURRENT:LEVEL:IMMEDIATE:AMPLITUDE", Some(argument)) => {
+                match argument.parse::<f64>() {
+                    Ok(amps) if amps >= 0.0 && amps <= self.current_limit_amps => self.current_amps = amps,
+                    _ => self.push_error(-222, "Data out of range"),
+                }
+            }
+            ("SOURCE:CURRENT:LIMIT:AMPLITUDE?", None) | ("SOURCE:CURRENT:LIMIT?", None) => {
+                self.respond(format!("{:.6E}", self.current_limit_amps));
+            }
+            ("SOURCE:CURRENT:LIMIT:AMPLITUDE", Some(argument)) | ("SOURCE:CURRENT:LIMIT", Some(argument)) => {
+                // The CLD1015 accepts limits up to its 1.5 A rating
+                match argument.parse::<f64>() {
+                    Ok(amps) if (0.0..=1.5).contains(&amps) => self.current_limit_amps = amps,
+                    _ => self.push_error(-222, "Data out of range"),
+                }
+            }
+            ("OUTPUT:PROTECTION:INTLOCK:TRIPPED?", None) => self.respond_tripped(Protection::Interlock),
+            ("OUTPUT:PROTECTION:OTEMP:TRIPPED?", None) => self.respond_tripped(Protection::OverTemperature),
+            ("OUTPUT:PROTECTION:OCIRCUIT:TRIPPED?", None) => self.respond_tripped(Protection::OpenCircuit),
+            ("MEASURE:TEMPERATURE?", None) | ("MEAS:TEMP?", None) => {
+                self.respond(format!("{:.3}", self.temperature_c));
+            }
+            ("MEASURE:CURRENT?", None) | ("MEAS:CURR?", None) => {
+                self.respond(format!("{:.6E}", self.diode_current_amps()));
+            }
+            ("MEASURE:VOLTAGE?", None) | ("MEAS:VOLT?", None) => {
+                self.respond(format!("{:.4}", self.forward_voltage_v()));
+            }
+            ("MEASURE:CURRENT2?", None) | ("MEAS:CURR2?", None) => {
+                self.respond(format!("{:.6E}", self.photodiode_current_amps()));
+            }
+            ("MEASURE:POWER2?", None) | ("MEAS:POW2?", None) => {
+                let watts = self.photodiode_current_amps() / self.pd_responsivity_a_per_w;
+                self.respond(format!("{:.6E}", watts));
+            }
+            ("SENSE2:CORRECTION:POWER:PDIODE:RESPONSE?", None) => {
+                self.respond(format!("{:.6E}", self.pd_responsivity_a_per_w));
+            }
+            ("SENSE2:CORRECTION:POWER:PDIODE:RESPONSE", Some(argument)) => match argument.parse::<f64>() {
+                Ok(amps_per_watt) if amps_per_watt > 0.0 => self.pd_responsivity_a_per_w = amps_per_watt,
+                _ => self.push_error(-222, "Data out of range"),
+            },
+            ("MEASURE:RESISTANCE?", None) | ("MEAS:RES?", None) => {
+                self.respond(format!("{:.1}", self.thermistor_ohms()));
+            }
+            ("SOURCE2:TEMPERATURE:SPOINT?", None) => {
+                self.respond(format!("{:.3}", self.tec_setpoint_c));
+            }
+            ("SOURCE2:TEMPERATURE:SPOINT", Some(argument)) => match argument.parse::<f64>() {
+                Ok(celsius) if (5.0..=45.0).contains(&celsius) => self.tec_setpoint_c = celsius,
+                _ => self.push_error(-222, "Data out of range"),
+            },
+            ("SOURCE2:CURRENT:LIMIT:AMPLITUDE?", None) => {
+                self.respond(format!("{:.6E}", self.tec_current_limit_amps));
+            }
+            ("SOURCE2:CURRENT:LIMIT:AMPLITUDE", Some(argument)) => match argument.parse::<f64>() {
+                Ok(amps) if (0.0..=2.0).contains(&amps) => self.tec_current_limit_amps = amps,
+                _ => self.push_error(-222, "Data out of range"),
+            },
+            ("SOURCE:FUNCTION:MODE?", None) => {
+                let mode = match self.mode {
+                    OperatingMode::Current => "CURR",
+                    OperatingMode::Power => "POW",
+                };
+                self.respond(mode.into());
+            }
+            ("SOURCE:FUNCTION:MODE", Some(argument)) => match argument.to_ascii_uppercase().as_str() {
+                _ if self.laser_on => self.push_error(-221, "Settings conflict;output on"),
+                "CURRENT" | "CURR" => self.mode = OperatingMode::Current,
+                "POWER" | "POW" => self.mode = OperatingMode::Power,
+                _ => self.push_error(-224, "Illegal parameter value"),
+            },
+            ("SOURCE:POWER:LEVEL:IMMEDIATE:AMPLITUDE?", None) => {
+                self.respond(format!("{:.6E}", self.power_setpoint_w));
+            }
+            ("SOURCE:POWER:LEVEL:IMMEDIATE:AMPLITUDE", Some(argument)) => match argument.parse::<f64>() {
+                Ok(watts) if watts >= 0.0 => self.power_setpoint_w = watts,
+                _ => self.push_error(-222, "Data out of range"),
+            },
+            ("INPUT:PDIODE:POLARITY?", None) => {
+                let polarity = match self.pd_polarity {
+                    PhotodiodePolarity::CathodeGrounded => "CG",
+                    PhotodiodePolarity::AnodeGrounded => "AG",
+                };
+                self.respond(polarity.into());
+            }
+            ("INPUT:PDIODE:POLARITY", Some(argument)) => match argument.to_ascii_uppercase().as_str() {
+                "CG" => self.pd_polarity = PhotodiodePolarity::CathodeGrounded,
+                "AG" => self.pd_polarity = PhotodiodePolarity::AnodeGrounded,
+                _ => self.push_error(-224, "Illegal parameter value"),
+            },
+            _ => self.push_error(-113, "Undefined header"),
+        }
+    }
+}
+
+fn parse_bool(argument: &str) -> Option<bool> {
+    match argument.to_ascii_uppercase().as_str() {
+        "ON" | "1" => Some(true),
+        "OFF" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+impl Write for SimulatedCLD1015 {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        state.input.extend_from_slice(buf);
+        while let Some(pos) = state.input.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = state.input.drain(..=pos).collect();
+            let command = String::from_utf8_lossy(&line).to_string();
+            state.handle(&command);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for SimulatedCLD1015 {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.output.is_empty() {
+            // Same behaviour as a VISA read on an instrument with nothing to say
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Simulated CLD1015 read timed out"));
+        }
+        let n = buf.len().min(state.output.len());
+        for (slot, byte) in buf.iter_mut().zip(state.output.drain(..n)) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::CLD1015;
+    use crate::error::LabError;
+
+    /// A simulator behind a connected driver, with the TEC switched on.
+    fn start(model: LaserDiodeModel) -> (SimulatedCLD1015, CLD1015) {
+        let simulator = SimulatedCLD1015::new(model);
+        let mut cld = CLD1015::simulated(simulator.clone());
+        cld.connect().unwrap();
+        cld.enable_tec().unwrap();
+        (simulator, cld)
+    }
+
+    fn settled() -> LaserDiodeModel {
+        LaserDiodeModel {
+            tec_warmup: Duration::ZERO,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn output_follows_the_diode_model() {
+        let model = settled();
+        let (simulator, mut cld) = start(model.clone());
+        cld.set_current(0.02).unwrap();
+        cld.set_laser_output(true).unwrap();
+        cld.check_error().unwrap();
+        assert!(simulator.optical_power_w().abs() < 1e-12);
+        assert!(cld.measure_photodiode_current().unwrap().abs() < 1e-12);
+
+        cld.set_current(0.08).unwrap();
+        let expected_w = (0.08 - model.threshold_current_amps) * model.slope_efficiency_w_per_a;
+        assert!((simulator.optical_power_w() - expected_w).abs() < 1e-9);
+        let photodiode_amps = cld.measure_photodiode_current().unwrap();
+        assert!((photodiode_amps - expected_w * model.monitor_coupling_a_per_w).abs() < 1e-6);
+        assert!((cld.measure_current().unwrap() - 0.08).abs() < 1e-6);
+    }
+
+    #[test]
+    fn output_is_refused_while_the_tec_warms_up() {
+        let (_simulator, mut cld) = start(LaserDiodeModel {
+            tec_warmup: Duration::from_secs(60),
+            ..Default::default()
+        });
+        cld.set_current(0.05).unwrap();
+        cld.set_laser_output(true).unwrap();
+
+        assert!(!cld.get_laser_output().unwrap());
+        assert!(matches!(cld.check_error(), Err(LabError::InstrumentError { code: -221, .. })));
+        cld.check_error().unwrap();
+    }
+
+    #[test]
+    fn constant_power_loop_reaches_the_setpoint() {
+        let model = settled();
+        let (simulator, mut cld) = start(model.clone());
+        cld.set_operating_mode(OperatingMode::Power).unwrap();
+        cld.set_photodiode_responsivity(model.monitor_coupling_a_per_w).unwrap();
+        cld.set_power(0.01).unwrap();
+        cld.set_laser_output(true).unwrap();
+        cld.check_error().unwrap();
+
+        assert!((cld.measure_photodiode_power().unwrap() - 0.01).abs() < 1e-6);
+        assert!((simulator.optical_power_w() - 0.01).abs() < 1e-9);
+        let expected_amps = model.threshold_current_amps + 0.01 / model.slope_efficiency_w_per_a;
+        assert!((cld.measure_current().unwrap() - expected_amps).abs() < 1e-6);
+    }
+
+    #[test]
+    fn tripped_protection_switches_the_output_off() {
+        let (simulator, mut cld) = start(settled());
+        cld.set_current(0.05).unwrap();
+        cld.set_laser_output(true).unwrap();
+        assert!(cld.get_laser_output().unwrap());
+
+        simulator.set_protection(Protection::OverTemperature, true);
+        assert!(!cld.get_laser_output().unwrap());
+        assert_eq!(cld.measure_current().unwrap(), 0.0);
+        assert_eq!(cld.tripped_protections().unwrap(), vec![Protection::OverTemperature]);
+        assert!(matches!(cld.set_laser_output(true), Err(LabError::ProtectionTripped { .. })));
+
+        simulator.set_protection(Protection::OverTemperature, false);
+        cld.set_laser_output(true).unwrap();
+        assert!(cld.get_laser_output().unwrap());
+    }
+
+    #[test]
+    fn temperature_wait_holds_within_tolerance() {
+        let (_simulator, mut cld) = start(LaserDiodeModel {
+            tec_time_constant: Duration::from_millis(50),
+            ..settled()
+        });
+        cld.set_tec_setpoint(30.0).unwrap();
+        let mut reports = Vec::new();
+        let temperature_c = cld
+            .wait_for_temperature_stable(0.1, Duration::from_millis(500), Duration::from_secs(5), &mut |progress| {
+                reports.push(progress.clone());
+                true
+            })
+            .unwrap();
+
+        assert!((temperature_c - 30.0).abs() <= 0.1);
+        let last = reports.last().unwrap();
+        assert!(last.stable_ms >= 500);
+        assert_eq!(last.hold_ms, 500);
+        // The hold only counts readings within tolerance
+        assert!(last.elapsed_ms >= last.stable_ms);
+        assert!(reports.iter().all(|r| r.stable_ms == 0 || (r.temperature_c - 30.0).abs() <= 0.1));
+    }
+
+    #[test]
+    fn temperature_wait_times_out() {
+        let (_simulator, mut cld) = start(LaserDiodeModel {
+            tec_time_constant: Duration::from_secs(60),
+            ..settled()
+        });
+        cld.set_tec_setpoint(40.0).unwrap();
+        let result =
+            cld.wait_for_temperature_stable(0.1, Duration::from_secs(1), Duration::from_millis(300), &mut |_| true);
+
+        match result {
+            Err(LabError::Timeout { command, timeout_ms, .. }) => {
+                assert_eq!(command, "temperature stabilization");
+                assert_eq!(timeout_ms, 300);
+            }
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn temperature_wait_can_be_cancelled() {
+        let (_simulator, mut cld) = start(LaserDiodeModel {
+            tec_time_constant: Duration::from_secs(60),
+            ..settled()
+        });
+        cld.set_tec_setpoint(40.0).unwrap();
+        let mut readings = 0;
+        let result = cld.wait_for_temperature_stable(0.1, Duration::from_secs(1), Duration::from_secs(60), &mut |_| {
+            readings += 1;
+            readings < 2
+        });
+
+        assert!(matches!(result, Err(LabError::Aborted)));
+        assert_eq!(readings, 2);
+    }
+}
@@ -0,0 +1,368 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+use super::transport::STB_ERROR_QUEUE;
+
+/// Optical power in watts seen by a given module/port.
+pub type PowerSource = Arc<dyn Fn(u8, u8) -> f64 + Send + Sync>;
+
+/// Behaviour of the emulated power meter.
+#[derive(Clone)]
+pub struct SimulatedMPM210HConfig {
+    /// Slots of the mainframe that hold a module
+    pub modules: Vec<u8>,
+    pub ports_per_module: u8,
+    /// Standard deviation of the noise added to every reading, in dB
+    pub noise_db: f64,
+    /// Delay before every reply is written
+    pub latency: Duration,
+    /// Probability (0..1) that a command fails with an execution error
+    pub error_rate: f64,
+    pub power_source: PowerSource,
+}
+
+impl Default for SimulatedMPM210HConfig {
+    fn default() -> Self {
+        SimulatedMPM210HConfig {
+            modules: vec![0, 1],
+            ports_per_module: 4,
+            noise_db: 0.01,
+            latency: Duration::from_millis(2),
+            error_rate: 0.0,
+            power_source: Arc::new(|_, _| 0.0),
+        }
+    }
+}
+
+/// Readings below this level are reported at the floor, in dBm.
+const NOISE_FLOOR_DBM: f64 = -80.0;
+
+struct MeterState {
+    config: SimulatedMPM210HConfig,
+    wavelength_nm: f64,
+    // false: dBm, true: mW
+    linear_unit: bool,
+    errors: VecDeque<String>,
+    rng: u64,
+}
+
+/// Localhost TCP server answering the MPM-210H command subset used by the
+/// `MPM210H` driver. The server stops when the handle is dropped.
+pub struct SimulatedMPM210H {
+    addr: SocketAddr,
+    state: Arc<Mutex<MeterState>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl SimulatedMPM210H {
+    /// Binds to `127.0.0.1:port` (0 picks a free port) and starts serving.
+    pub fn start(port: u16, config: SimulatedMPM210HConfig) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        info!("Simulated MPM210H listening on {}", addr);
+
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0x9E37_79B9_7F4A_7C15);
+        let state = Arc::new(Mutex::new(MeterState {
+            config,
+            wavelength_nm: 1550.0,
+            linear_unit: false,
+            errors: VecDeque::new(),
+            rng: seed | 1,
+        }));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let accept_state = state.clone();
+        let accept_shutdown = shutdown.clone();
+        thread::spawn(move || accept_loop(listener, accept_state, accept_shutdown));
+
+        Ok(SimulatedMPM210H { addr, state, shutdown })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Queues an error that the next `ERR?` query will return.
+    pub fn inject_error(&self, code: i32, message: &str) {
+        self.state.lock().unwrap().push_error(code, message);
+    }
+}
+
+impl Drop for SimulatedMPM210H {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+    }
+}
+
+fn accept_loop(listener: TcpListener, state: Arc<Mutex<MeterState>>, shutdown: Arc<AtomicBool>) {
+    while !shutdown.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                info!("Simulated MPM210H accepted connection from {}", peer);
+                let state = state.clone();
+                let shutdown = shutdown.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream, state, shutdown) {
+                        warn!("Simulated MPM210H connection closed: {}", e);
+                    }
+                });
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(20));
+            }
+            Err(e) => {
+                error!("Simulated MPM210H accept failed: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+fn serve(stream: TcpStream, state: Arc<Mutex<MeterState>>, shutdown: Arc<AtomicBool>) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+
+    while !shutdown.load(Ordering::Relaxed) {
+        match reader.read_line(&mut line) {
+            Ok(0) => return Ok(()), // client hung up
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e),
+        }
+        if !line.ends_with('\n') {
+            continue; // partial line, keep reading
+        }
+
+        let (reply, latency) = {
+            let mut state = state.lock().unwrap();
+            (state.handle(line.trim()), state.config.latency)
+        };
+        line.clear();
+
+        if let Some(reply) = reply {
+            thread::sleep(latency);
+            writer.write_all(format!("{}\n", reply).as_bytes())?;
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+impl MeterState {
+    fn push_error(&mut self, code: i32, message: &str) {
+        warn!("Simulated MPM210H error {}: {}", code, message);
+        self.errors.push_back(format!("{},\"{}\"", code, message));
+    }
+
+    /// xorshift64*, good enough for measurement noise
+    fn next_uniform(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let value = self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        (value >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Standard normal sample (Box-Muller)
+    fn next_gaussian(&mut self) -> f64 {
+        let u1 = self.next_uniform().max(f64::MIN_POSITIVE);
+        let u2 = self.next_uniform();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+
+    fn read_module(&mut self, module: u8) -> String {
+        let ports = self.config.ports_per_module;
+        let source = self.config.power_source.clone();
+        (1..=ports)
+            .map(|port| {
+                let power_mw = source(module, port) * 1000.0;
+                let dbm = if power_mw > 0.0 {
+                    (10.0 * power_mw.log10()).max(NOISE_FLOOR_DBM)
+                } else {
+                    NOISE_FLOOR_DBM
+                };
+                let noisy = dbm + self.next_gaussian() * self.config.noise_db;
+                if self.linear_unit {
+                    format!("{:.6E}", 10f64.powf(noisy / 10.0))
+                } else {
+                    format!("{:.3}", noisy)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn handle(&mut self, line: &str) -> Option<String> {
+        if line.is_empty() {
+            return None;
+        }
+        let (header, argument) = match line.split_once(char::is_whitespace) {
+            Some((header, argument)) => (header.to_ascii_uppercase(), Some(argument.trim())),
+            None => (line.to_ascii_uppercase(), None),
+        };
+        let is_query = header.ends_with('?');
+
+        // Status queries never fail, so they do not disturb what they report on
+        if header != "ERR?" && header != "*STB?" && self.config.error_rate > 0.0 && self.next_uniform() < self.config.error_rate {
+            self.push_error(-200, "Execution error");
+            // A failed query still answers so the client does not hang
+            return is_query.then(|| "-200".to_string());
+        }
+
+        match (header.as_str(), argument) {
+            ("*IDN?", None) => Some("santec,MPM-210H,SIM00000,Ver1.00".into()),
+            ("IDIS?", None) => {
+                let slots = (0..5u8)
+                    .map(|slot| if self.config.modules.contains(&slot) { "1" } else { "0" })
+                    .collect::<Vec<_>>()
+                    .join(",");
+                Some(slots)
+            }
+            ("ZERO", None) => {
+                info!("Simulated MPM210H zeroing");
+                None
+            }
+            ("WAV?", None) => Some(format!("{:.3}", self.wavelength_nm)),
+            ("WAV", Some(argument)) => {
+                match argument.parse::<f64>() {
+                    Ok(nm) if (900.0..=1700.0).contains(&nm) => self.wavelength_nm = nm,
+                    _ => self.push_error(-222, "Data out of range"),
+                }
+                None
+            }
+            ("UNIT?", None) => Some(if self.linear_unit { "1" } else { "0" }.into()),
+            ("UNIT", Some(argument)) => {
+                match argument {
+                    "0" => self.linear_unit = false,
+                    "1" => self.linear_unit = true,
+                    _ => self.push_error(-224, "Illegal parameter value"),
+                }
+                None
+            }
+            ("READ?", Some(argument)) => match argument.parse::<u8>() {
+                Ok(module) if self.config.modules.contains(&module) => Some(self.read_module(module)),
+                _ => {
+                    self.push_error(-222, "Data out of range");
+                    Some(String::new())
+                }
+            },
+            ("*STB?", None) => Some(if self.errors.is_empty() { 0 } else { STB_ERROR_QUEUE }.to_string()),
+            ("ERR?", None) => Some(self.errors.pop_front().unwrap_or_else(|| "0,\"No error\"".into())),
+            _ => {
+                self.push_error(-113, "Undefined header");
+                is_query.then(String::new)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{PowerUnit, ReadingStatus, MPM210H};
+    use crate::error::LabError;
+
+    /// A quiet meter seeing 1 mW on module 1 port 1 and nothing elsewhere.
+    fn start(latency: Duration) -> SimulatedMPM210H {
+        SimulatedMPM210H::start(
+            0,
+            SimulatedMPM210HConfig {
+                noise_db: 0.0,
+                latency,
+                power_source: Arc::new(|module, port| if (module, port) == (1, 1) { 1e-3 } else { 0.0 }),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    }
+
+    fn connect(meter: &SimulatedMPM210H) -> MPM210H {
+        let mut mpm = MPM210H::new("127.0.0.1", meter.local_addr().port());
+        mpm.connect().unwrap();
+        mpm
+    }
+
+    #[test]
+    fn driver_reads_every_module_over_tcp() {
+        let meter = start(Duration::ZERO);
+        let mut mpm = connect(&meter);
+
+        assert_eq!(mpm.get_recognized_modules().unwrap(), "1,1,0,0,0");
+        mpm.set_wavelength(1310).unwrap();
+        assert_eq!(mpm.get_wavelength().unwrap(), "1310.000");
+        mpm.perform_zeroing().unwrap();
+
+        let readings = mpm.read_modules(&[0, 1]).unwrap();
+        assert_eq!(readings.len(), 8);
+        let lit = readings.iter().find(|r| (r.module, r.port) == (1, 1)).unwrap();
+        assert_eq!(lit.dbm(), Some(0.0));
+        let dark = readings.iter().find(|r| (r.module, r.port) == (0, 2)).unwrap();
+        assert_eq!(dark.dbm(), Some(NOISE_FLOOR_DBM));
+        mpm.check_error().unwrap();
+    }
+
+    #[test]
+    fn driver_follows_linear_unit() {
+        let meter = start(Duration::ZERO);
+        let mut mpm = connect(&meter);
+        mpm.set_unit(PowerUnit::MilliWatt).unwrap();
+        let readings = mpm.read_power(1).unwrap();
+        assert!((readings[0].value - 1.0).abs() < 1e-6);
+        assert_eq!(readings[0].status, ReadingStatus::Ok);
+
+        // A new session picks up the unit left on the meter
+        let mut other = connect(&meter);
+        let readings = other.read_power(1).unwrap();
+        assert_eq!(readings[0].unit, PowerUnit::MilliWatt);
+        assert_eq!(readings[0].dbm(), Some(0.0));
+    }
+
+    #[test]
+    fn driver_reports_meter_errors() {
+        let meter = start(Duration::ZERO);
+        let mut mpm = connect(&meter);
+
+        mpm.send_command("WAV 5000").unwrap();
+        assert!(matches!(mpm.check_error(), Err(LabError::InstrumentError { code: -222, .. })));
+        meter.inject_error(-310, "System error");
+        assert!(matches!(mpm.check_error(), Err(LabError::InstrumentError { code: -310, .. })));
+        mpm.check_error().unwrap();
+        assert!(mpm.read_power(4).is_err());
+    }
+
+    #[test]
+    fn status_byte_leaves_errors_queued() {
+        let meter = start(Duration::ZERO);
+        let mut mpm = connect(&meter);
+
+        assert!(!mpm.error_pending().unwrap());
+        meter.inject_error(-310, "System error");
+        assert!(mpm.error_pending().unwrap());
+        assert!(mpm.error_pending().unwrap());
+        assert!(matches!(mpm.check_error(), Err(LabError::InstrumentError { code: -310, .. })));
+        assert!(!mpm.error_pending().unwrap());
+    }
+
+    #[test]
+    fn slow_reply_is_discarded_after_timeout() {
+        let meter = start(Duration::from_millis(80));
+        let mut mpm = connect(&meter);
+        mpm.set_command_timeout("READ?", Duration::from_millis(50));
+
+        assert!(matches!(mpm.read_power(1), Err(LabError::Timeout { .. })));
+        assert_eq!(mpm.get_wavelength().unwrap(), "1550.000");
+    }
+}
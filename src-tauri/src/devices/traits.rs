@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use super::cld1015::{OperatingMode, TemperatureProgress};
+use super::power::PowerReading;
+use crate::error::Result;
+use crate::monitor::LaserTelemetry;
+
+/// Common interface for laser diode controllers.
+///
+/// Currents are always expressed in amperes, as on the instrument side.
+pub trait LaserDriver {
+    fn set_current(&mut self, current_amps: f64) -> Result<()>;
+    fn get_current(&mut self) -> Result<f64>;
+
+    /// Constant-current or constant-power operation; changed with the output off.
+    fn set_operating_mode(&mut self, mode: OperatingMode) -> Result<()>;
+    /// Power setpoint for constant-power mode, in W at the monitor photodiode.
+    fn set_power(&mut self, power_watts: f64) -> Result<()>;
+
+    fn set_laser_output(&mut self, enabled: bool) -> Result<()>;
+    fn get_laser_output(&mut self) -> Result<bool>;
+
+    /// Current flowing through the laser, as opposed to the setpoint.
+    fn measure_current(&mut self) -> Result<f64>;
+    fn get_forward_voltage(&mut self) -> Result<f64>;
+
+    /// Monitor photodiode current in A, and the optical power derived from it
+    /// in W.
+    fn measure_photodiode_current(&mut self) -> Result<f64>;
+    fn measure_photodiode_power(&mut self) -> Result<f64>;
+
+    /// Switches the output off immediately, skipping any current ramp.
+    fn emergency_off(&mut self) -> Result<()>;
+
+    fn enable_tec(&mut self) -> Result<()>;
+    fn get_tec_state(&mut self) -> Result<bool>;
+
+    /// Fails with `LabError::SafetyInterlock` if the safety rules would not
+    /// allow running at `current_amps` with the output on.
+    fn check_interlocks(&mut self, current_amps: f64) -> Result<()>;
+    /// Same as `check_interlocks` for constant-power operation at `power_watts`.
+    fn check_power_interlocks(&mut self, power_watts: f64) -> Result<()>;
+
+    /// Waits until the diode temperature holds at the TEC setpoint; see
+    /// `CLD1015::wait_for_temperature_stable`.
+    fn wait_for_temperature_stable(
+        &mut self,
+        tolerance_c: f64,
+        hold_time: Duration,
+        timeout: Duration,
+        progress: &mut dyn FnMut(&TemperatureProgress) -> bool,
+    ) -> Result<f64>;
+
+    /// Fails with `LabError::ProtectionTripped` if a hardware protection has
+    /// switched the output off.
+    fn check_protection(&mut self) -> Result<()>;
+
+    /// Fails with `LabError::InstrumentError` if the instrument has an error queued.
+    fn check_error(&mut self) -> Result<()>;
+
+    /// Samples the state reported in the `device-telemetry` event. A failed
+    /// read ends the sample early and is reported in it, not returned.
+    fn telemetry(&mut self) -> LaserTelemetry;
+}
+
+/// Common interface for optical power meters.
+pub trait PowerMeter {
+    /// Reads every port of `module`.
+    fn read_power(&mut self, module: u8) -> Result<Vec<PowerReading>>;
+
+    /// Reads every port of each module in `modules`. Drivers that can batch
+    /// the requests into one round trip should override this.
+    fn read_modules(&mut self, modules: &[u8]) -> Result<Vec<PowerReading>> {
+        let mut readings = Vec::new();
+        for &module in modules {
+            readings.extend(self.read_power(module)?);
+        }
+        Ok(readings)
+    }
+
+    fn set_wavelength(&mut self, wavelength: u32) -> Result<()>;
+    fn perform_zeroing(&mut self) -> Result<()>;
+
+    /// Fails with `LabError::InstrumentError` if the instrument has an error queued.
+    fn check_error(&mut self) -> Result<()>;
+}
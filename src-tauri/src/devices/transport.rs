@@ -0,0 +1,448 @@
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CString;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+use visa_rs::attribute::AttrTmoValue;
+use visa_rs::prelude::*;
+
+use super::simulated_cld1015::SimulatedCLD1015;
+use crate::error::{LabError, Result};
+
+/// Terminator of SCPI commands and responses on every link.
+const TERMINATOR: u8 = b'\n';
+/// Stale input is drained until the link has been quiet for this long.
+const DRAIN_QUIET: Duration = Duration::from_millis(50);
+/// Bit of the IEEE 488.2 status byte (`*STB?`) set while the SCPI error
+/// queue is not empty.
+pub const STB_ERROR_QUEUE: u8 = 0x04;
+
+/// Line-oriented SCPI link to an instrument.
+///
+/// Commands are written with the transport's terminator appended and
+/// responses are returned without their terminator. A read that does not
+/// see a complete line in time fails with `LabError::Timeout`.
+pub trait ScpiTransport: Send {
+    fn write_line(&mut self, command: &str) -> Result<()>;
+    fn read_line_timeout(&mut self, timeout: Duration) -> Result<String>;
+
+    /// Default response timeout of this link.
+    fn timeout(&self) -> Duration;
+
+    fn read_line(&mut self) -> Result<String> {
+        let timeout = self.timeout();
+        self.read_line_timeout(timeout)
+    }
+
+    fn query(&mut self, command: &str) -> Result<String> {
+        let timeout = self.timeout();
+        self.query_timeout(command, timeout)
+    }
+
+    fn query_timeout(&mut self, command: &str, timeout: Duration) -> Result<String> {
+        self.write_line(command)?;
+        self.read_line_timeout(timeout).map_err(|e| match e {
+            LabError::Timeout { device, timeout_ms, .. } => LabError::Timeout {
+                device,
+                command: command.to_string(),
+                timeout_ms,
+            },
+            other => other,
+        })
+    }
+}
+
+/// Byte streams that can bound how long a single read blocks.
+pub trait TimedStream: Read + Write + Send {
+    fn set_read_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+}
+
+impl TimedStream for TcpStream {
+    fn set_read_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, Some(timeout))
+    }
+}
+
+impl TimedStream for Box<dyn serialport::SerialPort> {
+    fn set_read_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        Ok(self.set_timeout(timeout)?)
+    }
+}
+
+impl TimedStream for Instrument {
+    fn set_read_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        // A VISA read blocks for the session I/O timeout (VI_ATTR_TMO_VALUE),
+        // in whole ms; zero would make every read fail at once
+        let timeout_ms = timeout.as_millis().clamp(1, u32::MAX as u128) as u32;
+        let attribute = AttrTmoValue::new_checked(timeout_ms)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("VISA timeout {} ms", timeout_ms)))?;
+        self.set_attr(attribute).map_err(visa_rs::vs_to_io_err)
+    }
+}
+
+impl TimedStream for SimulatedCLD1015 {
+    fn set_read_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How to reach an instrument. `open` creates a fresh transport each time the
+/// driver connects.
+#[derive(Clone)]
+pub enum TransportSpec {
+    Visa { resource: String, timeout: Duration },
+    Tcp { address: String, timeout: Duration },
+    Serial { port: String, baud_rate: u32, timeout: Duration },
+    SimulatedCLD1015(SimulatedCLD1015),
+    Mock(MockTransport),
+}
+
+impl TransportSpec {
+    pub fn open(&self) -> Result<Box<dyn ScpiTransport>> {
+        match self {
+            TransportSpec::Visa { resource, timeout } => {
+                let rm = DefaultRM::new()?;
+                let resource_id = CString::new(resource.clone())
+                    .map_err(|e| LabError::InvalidParameter(format!("VISA resource {}: {}", resource, e)))?;
+                // The timeout passed here only bounds opening the session
+                let mut device = rm.open(&resource_id.into(), AccessMode::NO_LOCK, Duration::from_secs(1))?;
+                device.set_read_timeout(*timeout)?;
+                Ok(Box::new(StreamTransport::new(resource.clone(), device, *timeout)))
+            }
+            TransportSpec::Tcp { address, timeout } => {
+                let socket_addr: SocketAddr = address
+                    .parse()
+                    .map_err(|e| LabError::InvalidParameter(format!("Address {}: {}", address, e)))?;
+                let stream = TcpStream::connect_timeout(&socket_addr, *timeout)?;
+                stream.set_nodelay(true)?;
+                Ok(Box::new(StreamTransport::new(address.clone(), stream, *timeout)))
+            }
+            TransportSpec::Serial { port, baud_rate, timeout } => {
+                let serial = serialport::new(port, *baud_rate)
+                    .timeout(*timeout)
+                    .open()
+                    .map_err(io::Error::from)?;
+                Ok(Box::new(StreamTransport::new(port.clone(), serial, *timeout)))
+            }
+            TransportSpec::SimulatedCLD1015(simulator) => {
+                let name = "simulated CLD1015".to_string();
+                Ok(Box::new(StreamTransport::new(name, simulator.clone(), Duration::from_secs(1))))
+            }
+            TransportSpec::Mock(mock) => {
+                let name = "mock".to_string();
+                Ok(Box::new(StreamTransport::new(name, mock.clone(), mock.timeout())))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for TransportSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransportSpec::Visa { resource, .. } => write!(f, "VISA {}", resource),
+            TransportSpec::Tcp { address, .. } => write!(f, "TCP {}", address),
+            TransportSpec::Serial { port, baud_rate, .. } => write!(f, "serial {} @ {} Bd", port, baud_rate),
+            TransportSpec::SimulatedCLD1015(_) => write!(f, "simulated CLD1015"),
+            TransportSpec::Mock(_) => write!(f, "mock"),
+        }
+    }
+}
+
+/// SCPI over any byte stream: VISA instruments, TCP sockets, serial ports
+/// and the in-process simulator all go through here.
+///
+/// Responses are framed on `TERMINATOR` (a preceding `\r` is trimmed). Bytes
+/// received past the end of a line are kept for the next read, so replies
+/// split over several packets or merged into one are both handled.
+///
+/// Whatever is still buffered when the next command is written belongs to an
+/// earlier exchange and is discarded. After a read timed out, the late reply
+/// may still be on its way, so the stream itself is drained as well; this
+/// keeps every later reply matched to its own query, unless a reply comes
+/// more than twice its timeout late.
+pub struct StreamTransport<S: TimedStream> {
+    name: String,
+    stream: S,
+    rx: Vec<u8>,
+    timeout: Duration,
+    // Timeout of the last read if it expired, since its reply may still arrive
+    stale: Option<Duration>,
+}
+
+impl<S: TimedStream> StreamTransport<S> {
+    pub fn new(name: String, stream: S, timeout: Duration) -> Self {
+        StreamTransport {
+            name,
+            stream,
+            rx: Vec::new(),
+            timeout,
+            stale: None,
+        }
+    }
+
+    /// Removes and returns the first complete line in the receive buffer.
+    fn take_line(&mut self) -> Option<String> {
+        let pos = self.rx.iter().position(|&b| b == TERMINATOR)?;
+        let line: Vec<u8> = self.rx.drain(..=pos).collect();
+        Some(String::from_utf8_lossy(&line).trim().to_string())
+    }
+
+    /// Drops replies left over from earlier exchanges. After a timeout the
+    /// late reply is waited for as long as the read that gave up on it
+    /// waited, then the stream is drained until it stays quiet for
+    /// `DRAIN_QUIET`.
+    fn discard_stale_input(&mut self) -> Result<()> {
+        let mut discarded = std::mem::take(&mut self.rx);
+        if let Some(grace) = self.stale.take() {
+            let deadline = Instant::now() + grace;
+            let mut quiet_since = Instant::now();
+            let mut chunk = [0_u8; 4096];
+            loop {
+                let late_reply_seen = discarded.contains(&TERMINATOR);
+                if quiet_since.elapsed() >= DRAIN_QUIET && (late_reply_seen || Instant::now() >= deadline) {
+                    break;
+                }
+                self.stream.set_read_timeout(DRAIN_QUIET)?;
+                match self.stream.read(&mut chunk) {
+                    // A closed link is reported by the next read
+                    Ok(0) => break,
+                    Ok(n) => {
+                        discarded.extend_from_slice(&chunk[..n]);
+                        quiet_since = Instant::now();
+                    }
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        error!("Failed to read from {}: {}", self.name, e);
+                        return Err(LabError::Io(e));
+                    }
+                }
+            }
+        }
+        if !discarded.is_empty() {
+            warn!(
+                "Discarded stale input from {}: {}",
+                self.name,
+                String::from_utf8_lossy(&discarded).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<S: TimedStream> ScpiTransport for StreamTransport<S> {
+    fn write_line(&mut self, command: &str) -> Result<()> {
+        self.discard_stale_input()?;
+        info!("Sending command to {}: {}", self.name, command);
+        let mut line = command.as_bytes().to_vec();
+        line.push(TERMINATOR);
+        self.stream
+            .write_all(&line)
+            .and_then(|_| self.stream.flush())
+            .map_err(|e| {
+                error!("Failed to write to {}: {}", self.name, e);
+                LabError::Io(e)
+            })
+    }
+
+    fn read_line_timeout(&mut self, timeout: Duration) -> Result<String> {
+        let deadline = Instant::now() + timeout;
+        let mut chunk = [0_u8; 4096];
+        loop {
+            if let Some(line) = self.take_line() {
+                info!("Received response from {}: {}", self.name, line);
+                return Ok(line);
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                warn!(
+                    "No response from {} within {} ms ({} bytes pending)",
+                    self.name,
+                    timeout.as_millis(),
+                    self.rx.len()
+                );
+                self.stale = Some(timeout);
+                return Err(LabError::Timeout {
+                    device: self.name.clone(),
+                    command: String::new(),
+                    timeout_ms: timeout.as_millis() as u64,
+                });
+            }
+
+            self.stream.set_read_timeout(remaining)?;
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    error!("{} closed the connection", self.name);
+                    return Err(LabError::Io(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Connection closed by instrument",
+                    )));
+                }
+                Ok(n) => self.rx.extend_from_slice(&chunk[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    // Streams without a blocking timeout return straight away
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    error!("Failed to read from {}: {}", self.name, e);
+                    return Err(LabError::Io(e));
+                }
+            }
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    replies: HashMap<String, VecDeque<MockReply>>,
+    // Bytes written since the last terminator
+    line: Vec<u8>,
+    // Chunks ready to be read, one per `read` call
+    output: VecDeque<Vec<u8>>,
+    // Late replies with the time they arrive
+    delayed: Vec<(Instant, Vec<u8>)>,
+    written: Vec<String>,
+}
+
+struct MockReply {
+    chunks: Vec<Vec<u8>>,
+    delay: Duration,
+}
+
+/// In-memory instrument with scripted replies, for testing the drivers.
+///
+/// It is a byte stream behind a `StreamTransport`, so framing, timeouts and
+/// stale replies are handled by the same code as on a real link. Cloning
+/// yields another handle to the same script, so the commands a driver sent
+/// can be inspected.
+#[derive(Clone)]
+pub struct MockTransport {
+    state: Arc<Mutex<MockState>>,
+    timeout: Duration,
+}
+
+impl Default for MockTransport {
+    fn default() -> Self {
+        Self::with_timeout(Duration::from_secs(1))
+    }
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A mock whose link times out reads after `timeout`.
+    pub fn with_timeout(timeout: Duration) -> Self {
+        MockTransport {
+            state: Arc::default(),
+            timeout,
+        }
+    }
+
+    /// Queues `reply` as the response to the next `command` written.
+    pub fn reply(&self, command: &str, reply: &str) {
+        let line = format!("{}{}", reply, TERMINATOR as char);
+        self.reply_chunks(command, &[line.as_str()]);
+    }
+
+    /// Queues a raw response, terminators included, that arrives in
+    /// `chunks`: each chunk is returned by a separate read.
+    pub fn reply_chunks(&self, command: &str, chunks: &[&str]) {
+        self.push_reply(command, chunks, Duration::ZERO);
+    }
+
+    /// Queues `reply` to arrive `delay` after `command` is written.
+    pub fn reply_late(&self, command: &str, reply: &str, delay: Duration) {
+        let line = format!("{}{}", reply, TERMINATOR as char);
+        self.push_reply(command, &[line.as_str()], delay);
+    }
+
+    fn push_reply(&self, command: &str, chunks: &[&str], delay: Duration) {
+        let reply = MockReply {
+            chunks: chunks.iter().map(|chunk| chunk.as_bytes().to_vec()).collect(),
+            delay,
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .replies
+            .entry(command.to_string())
+            .or_default()
+            .push_back(reply);
+    }
+
+    /// Every command written so far, in order.
+    pub fn written(&self) -> Vec<String> {
+        self.state.lock().unwrap().written.clone()
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+impl Read for MockTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let (arrived, pending): (Vec<_>, Vec<_>) = state.delayed.drain(..).partition(|(at, _)| *at <= now);
+        state.delayed = pending;
+        state.output.extend(arrived.into_iter().map(|(_, bytes)| bytes));
+
+        let Some(mut chunk) = state.output.pop_front() else {
+            return Err(io::Error::from(ErrorKind::WouldBlock));
+        };
+        let n = chunk.len().min(buf.len());
+        buf[..n].copy_from_slice(&chunk[..n]);
+        if n < chunk.len() {
+            state.output.push_front(chunk.split_off(n));
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MockTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        for &byte in buf {
+            if byte != TERMINATOR {
+                state.line.push(byte);
+                continue;
+            }
+            let command = String::from_utf8_lossy(&std::mem::take(&mut state.line)).trim().to_string();
+            if let Some(reply) = state.replies.get_mut(&command).and_then(VecDeque::pop_front) {
+                if reply.delay.is_zero() {
+                    state.output.extend(reply.chunks);
+                } else {
+                    let at = Instant::now() + reply.delay;
+                    state.delayed.extend(reply.chunks.into_iter().map(|chunk| (at, chunk)));
+                }
+            }
+            state.written.push(command);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TimedStream for MockTransport {
+    fn set_read_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+        // Reads never block; `StreamTransport` polls until its deadline
+        Ok(())
+    }
+}
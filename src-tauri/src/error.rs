@@ -0,0 +1,120 @@
+use serde::ser::{SerializeMap, Serializer};
+use serde::Serialize;
+use thiserror::Error;
+
+/// Errors raised by the device drivers and experiments.
+///
+/// Serialized to the frontend as `{ "kind": ..., "message": ..., ...details }`
+/// so the UI can branch on `kind` instead of matching message text.
+#[derive(Error, Debug)]
+pub enum LabError {
+    #[error("{device} not connected")]
+    NotConnected { device: String },
+
+    #[error("{device} did not answer {command} within {timeout_ms} ms")]
+    Timeout {
+        device: String,
+        command: String,
+        timeout_ms: u64,
+    },
+
+    #[error("Safety interlock ({rule}): {reason}")]
+    SafetyInterlock { rule: String, reason: String },
+
+    /// A hardware protection of the instrument switched the output off
+    #[error("{device} protection tripped: {protection}")]
+    ProtectionTripped { device: String, protection: String },
+
+    #[error("Instrument error {code}: {message}")]
+    InstrumentError { code: i32, message: String },
+
+    #[error("Parse error: {0}")]
+    ParseError(String),
+
+    #[error("Invalid parameter: {0}")]
+    InvalidParameter(String),
+
+    #[error("Experiment aborted")]
+    Aborted,
+
+    /// The driver is in use by the running experiment
+    #[error("{device} is busy with the running experiment")]
+    Busy { device: String },
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, LabError>;
+
+impl LabError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LabError::NotConnected { .. } => "not_connected",
+            LabError::Timeout { .. } => "timeout",
+            LabError::SafetyInterlock { .. } => "safety_interlock",
+            LabError::ProtectionTripped { .. } => "protection_tripped",
+            LabError::InstrumentError { .. } => "instrument_error",
+            LabError::ParseError(_) => "parse_error",
+            LabError::InvalidParameter(_) => "invalid_parameter",
+            LabError::Aborted => "aborted",
+            LabError::Busy { .. } => "busy",
+            LabError::Io(_) => "io",
+        }
+    }
+
+    /// Parses an error queue entry such as `-113,"Undefined header"` as
+    /// returned by `SYST:ERR?` / `ERR?`. Returns `None` for "no error".
+    pub fn from_error_queue(entry: &str) -> Option<LabError> {
+        let entry = entry.trim();
+        let (code, message) = match entry.split_once(',') {
+            Some((code, message)) => (code.trim(), message.trim().trim_matches('"')),
+            None => (entry, ""),
+        };
+        match code.parse::<i32>() {
+            Ok(0) => None,
+            Ok(code) => Some(LabError::InstrumentError {
+                code,
+                message: message.to_string(),
+            }),
+            Err(_) if entry.to_lowercase().contains("no error") => None,
+            Err(_) => Some(LabError::ParseError(format!("Unexpected error queue entry: {}", entry))),
+        }
+    }
+}
+
+impl From<visa_rs::Error> for LabError {
+    fn from(err: visa_rs::Error) -> Self {
+        LabError::Io(visa_rs::vs_to_io_err(err))
+    }
+}
+
+impl Serialize for LabError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("kind", self.kind())?;
+        map.serialize_entry("message", &self.to_string())?;
+        match self {
+            LabError::NotConnected { device } | LabError::Busy { device } => {
+                map.serialize_entry("device", device)?;
+            }
+            LabError::Timeout { device, command, timeout_ms } => {
+                map.serialize_entry("device", device)?;
+                map.serialize_entry("command", command)?;
+                map.serialize_entry("timeout_ms", timeout_ms)?;
+            }
+            LabError::SafetyInterlock { rule, .. } => {
+                map.serialize_entry("rule", rule)?;
+            }
+            LabError::ProtectionTripped { device, protection } => {
+                map.serialize_entry("device", device)?;
+                map.serialize_entry("protection", protection)?;
+            }
+            LabError::InstrumentError { code, .. } => {
+                map.serialize_entry("code", code)?;
+            }
+            _ => {}
+        }
+        map.end()
+    }
+}
@@ -0,0 +1,110 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Shared between a running sweep and the commands that steer it.
+///
+/// The sweep polls `is_cancelled` and `is_paused` once per step and reports
+/// progress through `record_point`; `abort_experiment` cancels and then waits
+/// for `finish`.
+#[derive(Default)]
+pub struct SweepControl {
+    cancelled: AtomicBool,
+    // Cancelled by `emergency_stop`: shut the laser off without ramping
+    emergency: AtomicBool,
+    paused: AtomicBool,
+    points: AtomicUsize,
+    finished: Mutex<bool>,
+    changed: Condvar,
+}
+
+impl SweepControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        // Wake the sweep if it is sleeping in `wait`
+        let _guard = self.finished.lock().unwrap();
+        self.changed.notify_all();
+    }
+
+    /// Cancels the sweep and asks it to switch the laser off without ramping.
+    pub fn emergency_stop(&self) {
+        self.emergency.store(true, Ordering::SeqCst);
+        self.cancel();
+    }
+
+    pub fn is_emergency(&self) -> bool {
+        self.emergency.load(Ordering::SeqCst)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+        let _guard = self.finished.lock().unwrap();
+        self.changed.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Blocks while paused. Returns `true` if the sweep was cancelled in the
+    /// meantime.
+    pub fn wait_while_paused(&self) -> bool {
+        let mut finished = self.finished.lock().unwrap();
+        while self.is_paused() && !self.is_cancelled() {
+            finished = self.changed.wait(finished).unwrap();
+        }
+        self.is_cancelled()
+    }
+
+    pub fn record_point(&self) {
+        self.points.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn points(&self) -> usize {
+        self.points.load(Ordering::SeqCst)
+    }
+
+    /// Sleeps for `duration` unless cancelled first. Returns `true` if the
+    /// sweep was cancelled.
+    pub fn wait(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        let mut finished = self.finished.lock().unwrap();
+        while !self.is_cancelled() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            finished = self.changed.wait_timeout(finished, remaining).unwrap().0;
+        }
+        self.is_cancelled()
+    }
+
+    /// Marks the sweep as done, whatever the outcome.
+    pub fn finish(&self) {
+        *self.finished.lock().unwrap() = true;
+        self.changed.notify_all();
+    }
+
+    /// Blocks until `finish` is called or `timeout` elapses. Returns `true`
+    /// if the sweep finished.
+    pub fn wait_finished(&self, timeout: Duration) -> bool {
+        let finished = self.finished.lock().unwrap();
+        let (finished, _) = self
+            .changed
+            .wait_timeout_while(finished, timeout, |finished| !*finished)
+            .unwrap();
+        *finished
+    }
+}
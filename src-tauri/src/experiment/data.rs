@@ -1,11 +1,53 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::analysis::CurveAnalysis;
+use crate::devices::ReadingStatus;
+
+/// One power meter input acquired during a sweep.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Channel {
+    pub module: u8, // module ID on MPM-210H
+    pub port: u8, // port of the module, starting at 1
+}
 
 #[derive(Serialize)]
 pub struct MeasurementRecord {
     pub timestamp: String, // UTC ISO timestamp
     #[serde(rename = "current_mA")]
-    pub current_ma: f64, // laser input current
+    pub current_ma: Option<f64>, // laser current setpoint, empty in power sweeps
+    #[serde(rename = "power_setpoint_mW")]
+    pub power_setpoint_mw: Option<f64>, // power setpoint, only in power sweeps
+    #[serde(rename = "measured_current_mA")]
+    pub measured_current_ma: f64, // laser current read back from the CLD1015
+    #[serde(rename = "voltage_V")]
+    pub voltage_v: f64, // laser forward voltage
     #[serde(rename = "power_dBm")]
-    pub power_dbm: String, // MPM-210H output
-    pub module: u8, // port/module ID on MPM-210H
+    pub power_dbm: Option<f64>, // MPM-210H output, empty when out of range
+    pub status: Option<ReadingStatus>, // ok / overrange / underrange, empty without power meter
+    pub module: Option<u8>, // module ID on MPM-210H, empty without power meter
+    pub port: Option<u8>, // port of the module
+    #[serde(rename = "monitor_current_mA")]
+    pub monitor_current_ma: Option<f64>, // CLD1015 monitor photodiode current, when recorded
+    #[serde(rename = "monitor_power_dBm")]
+    pub monitor_power_dbm: Option<f64>, // monitor photodiode power, needs the profile's responsivity
+}
+
+/// Payload of the `sweep-state` event emitted when a sweep is paused or resumed.
+#[derive(Serialize, Clone)]
+pub struct SweepStateEvent {
+    pub state: &'static str, // "paused" or "resumed"
+    pub points: usize, // points collected so far
+    #[serde(rename = "setpoint_mA")]
+    pub setpoint_ma: Option<f64>, // last current set before the pause
+    #[serde(rename = "setpoint_mW")]
+    pub setpoint_mw: Option<f64>, // last power set before the pause, in power sweeps
+}
+
+/// Returned by a completed sweep: where its data went and the L-I analysis.
+#[derive(Serialize, Clone, Debug)]
+pub struct SweepOutcome {
+    pub data_path: PathBuf, // CSV of the measurement records
+    pub analysis_path: PathBuf, // JSON of `analysis`, next to the CSV
+    pub analysis: Vec<CurveAnalysis>,
 }
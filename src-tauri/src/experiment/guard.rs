@@ -0,0 +1,83 @@
+use std::ops::{Deref, DerefMut};
+use tracing::{error, info};
+
+use super::control::SweepControl;
+use crate::devices::LaserDriver;
+
+/// Keeps the laser safe for the lifetime of an experiment.
+///
+/// The guard wraps the laser driver while the output may be on. However the
+/// experiment ends (completion, error, `?`, panic, abort or emergency stop),
+/// dropping the guard switches the output off and zeroes the current, and
+/// records that in the `audit` log.
+pub struct LaserGuard<'a, L: LaserDriver> {
+    laser: &'a mut L,
+    control: &'a SweepControl,
+    completed: bool,
+}
+
+impl<'a, L: LaserDriver> LaserGuard<'a, L> {
+    pub fn new(laser: &'a mut L, control: &'a SweepControl) -> Self {
+        LaserGuard {
+            laser,
+            control,
+            completed: false,
+        }
+    }
+
+    /// Marks the run as completed, the cause recorded when the guard drops.
+    pub fn complete(&mut self) {
+        self.completed = true;
+    }
+
+    fn cause(&self) -> &'static str {
+        if std::thread::panicking() {
+            "panic"
+        } else if self.control.is_emergency() {
+            "emergency stop"
+        } else if self.control.is_cancelled() {
+            "abort"
+        } else if self.completed {
+            "completion"
+        } else {
+            "error"
+        }
+    }
+}
+
+impl<L: LaserDriver> Deref for LaserGuard<'_, L> {
+    type Target = L;
+
+    fn deref(&self) -> &L {
+        self.laser
+    }
+}
+
+impl<L: LaserDriver> DerefMut for LaserGuard<'_, L> {
+    fn deref_mut(&mut self) -> &mut L {
+        self.laser
+    }
+}
+
+impl<L: LaserDriver> Drop for LaserGuard<'_, L> {
+    fn drop(&mut self) {
+        let cause = self.cause();
+        let result = if self.control.is_emergency() {
+            self.laser.emergency_off()
+        } else {
+            self.laser
+                .set_laser_output(false)
+                .and_then(|_| self.laser.set_current(0.0))
+        };
+        match result {
+            Ok(()) => info!(target: "audit", "Laser guard: output off and current zeroed after {}", cause),
+            Err(e) => {
+                error!(target: "audit", "Laser guard could not shut down the laser after {}: {}", cause, e);
+                // Last resort, skips the current ramp
+                if let Err(e) = self.laser.emergency_off() {
+                    error!(target: "audit", "Laser guard emergency off failed: {}", e);
+                }
+            }
+        }
+    }
+}
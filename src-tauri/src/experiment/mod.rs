@@ -1,91 +1,481 @@
+pub mod control;
 pub mod data;
+pub mod guard;
 
-use crate::devices::{CLD1015, MPM210H};
-use data::MeasurementRecord;
+use crate::analysis::{self, CurveAnalysis};
+use crate::config::{ExperimentSettings, LaserProfile};
+use crate::devices::{LaserDriver, OperatingMode, PowerMeter, PowerReading};
+use crate::error::{LabError, Result};
+use crate::monitor::{DeviceTelemetry, MeterTelemetry};
+use control::SweepControl;
+use data::{Channel, MeasurementRecord, SweepOutcome, SweepStateEvent};
+use guard::LaserGuard;
 use chrono::Utc;
 use std::fs::File;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use csv::Writer;
-use tracing::{info, error};
+use tracing::{info, warn, error};
 use tauri::Window;
 use tauri::Emitter;
 
-/// Run a current sweep and collect measurements
-pub fn run_current_sweep_with_live_plot(
-    cld: &mut CLD1015,
-    mpm: &mut MPM210H,
-    module: u8,
+/// How often a sweep publishes the `device-telemetry` the health monitor
+/// cannot sample while the sweep holds the drivers.
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Run a current sweep and collect measurements.
+///
+/// Every channel in `channels` is read at each current step, with all modules
+/// queried in one round trip, and gets its own record in the events and CSV.
+/// The measured laser current and forward voltage of the step are stored with
+/// every record, giving a full L-I-V curve.
+/// With a laser `profile` the sweep must stay within the diode's rating.
+///
+/// The CLD1015 monitor photodiode is recorded as well when enabled in
+/// `settings`. Without `channels` the sweep records only the monitor
+/// photodiode, one record per step, and `mpm` is not used.
+///
+/// The sweep stops within one step once `control` is cancelled: the laser is
+/// switched off, the points collected so far are saved and `LabError::Aborted`
+/// is returned. A sweep that fails part way, e.g. on an instrument error,
+/// timeout or protection trip, also saves its points before returning the
+/// error; after `SweepControl::emergency_stop` the laser is shut off
+/// without ramping the current down. From the moment the laser is switched on
+/// it is held by a `LaserGuard`, so it is also shut off when the sweep fails
+/// or panics. While `control` is paused the laser is parked at zero current
+/// with the output off, and the last setpoint is restored on resume.
+///
+/// The L-I curves are analyzed and screened for kinks and rollover with
+/// `settings.analysis` (`analysis::analyze_sweep`), and the results are
+/// saved as JSON next to the CSV, also when the sweep was aborted.
+#[allow(clippy::too_many_arguments)]
+pub fn run_current_sweep_with_live_plot<L: LaserDriver, P: PowerMeter>(
+    cld: &mut L,
+    mpm: Option<&mut P>,
+    channels: &[Channel],
     start_ma: f64,
     stop_ma: f64,
     step_ma: f64,
-    stabilization_delay_ms: u64,
+    settings: &ExperimentSettings,
+    profile: Option<&LaserProfile>,
     window: Window,
-) -> Result<PathBuf, String> {
+    control: &SweepControl,
+) -> Result<SweepOutcome> {
+    let sweep = Sweep { axis: SweepAxis::Current, start: start_ma, stop: stop_ma, step: step_ma };
+    run_sweep(cld, mpm, channels, sweep, settings, profile, window, control)
+}
+
+/// Run a power sweep in constant-power mode and collect measurements.
+///
+/// The laser is switched to constant-power mode and the power setpoint
+/// (monitor photodiode power, in mW) steps from `start_mw` to `stop_mw`; the
+/// drive current the controller settles at is recorded as the measured
+/// current. Otherwise the same as `run_current_sweep_with_live_plot`. The
+/// laser stays in constant-power mode afterwards.
+#[allow(clippy::too_many_arguments)]
+pub fn run_power_sweep_with_live_plot<L: LaserDriver, P: PowerMeter>(
+    cld: &mut L,
+    mpm: Option<&mut P>,
+    channels: &[Channel],
+    start_mw: f64,
+    stop_mw: f64,
+    step_mw: f64,
+    settings: &ExperimentSettings,
+    profile: Option<&LaserProfile>,
+    window: Window,
+    control: &SweepControl,
+) -> Result<SweepOutcome> {
+    let sweep = Sweep { axis: SweepAxis::Power, start: start_mw, stop: stop_mw, step: step_mw };
+    run_sweep(cld, mpm, channels, sweep, settings, profile, window, control)
+}
+
+/// Setpoint a sweep steps through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SweepAxis {
+    /// Laser current in mA, in constant-current mode
+    Current,
+    /// Monitor photodiode power in mW, in constant-power mode
+    Power,
+}
+
+impl SweepAxis {
+    fn mode(self) -> OperatingMode {
+        match self {
+            SweepAxis::Current => OperatingMode::Current,
+            SweepAxis::Power => OperatingMode::Power,
+        }
+    }
+
+    fn unit(self) -> &'static str {
+        match self {
+            SweepAxis::Current => "mA",
+            SweepAxis::Power => "mW",
+        }
+    }
+
+    /// Drives the laser at `value`, in the unit of the axis.
+    fn apply<L: LaserDriver>(self, cld: &mut L, value: f64) -> Result<()> {
+        match self {
+            SweepAxis::Current => cld.set_current(value / 1000.0),
+            SweepAxis::Power => cld.set_power(value / 1000.0),
+        }
+    }
+
+    fn check_interlocks<L: LaserDriver>(self, cld: &mut L, value: f64) -> Result<()> {
+        match self {
+            SweepAxis::Current => cld.check_interlocks(value / 1000.0),
+            // The drive current is bounded by the hardware current limit
+            SweepAxis::Power => cld.check_power_interlocks(value / 1000.0),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Sweep {
+    axis: SweepAxis,
+    start: f64,
+    stop: f64,
+    step: f64,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_sweep<L: LaserDriver, P: PowerMeter>(
+    cld: &mut L,
+    mpm: Option<&mut P>,
+    channels: &[Channel],
+    sweep: Sweep,
+    settings: &ExperimentSettings,
+    profile: Option<&LaserProfile>,
+    window: Window,
+    control: &SweepControl,
+) -> Result<SweepOutcome> {
+    let axis = sweep.axis;
+    let unit = axis.unit();
     // Validate parameters
-    if step_ma <= 0.0 || start_ma > stop_ma {
-        return Err("Invalid sweep parameters".into());
+    let finite = [sweep.start, sweep.stop, sweep.step].iter().all(|v| v.is_finite());
+    if !finite || sweep.step <= 0.0 || sweep.start > sweep.stop {
+        return Err(LabError::InvalidParameter("Invalid sweep parameters".into()));
+    }
+    let mut mpm = if channels.is_empty() {
+        None
+    } else {
+        Some(mpm.ok_or_else(|| LabError::InvalidParameter("No power meter available for the selected channels".into()))?)
+    };
+    let record_monitor = mpm.is_none() || settings.record_monitor_photodiode;
+    // The controller only converts to power with the package's responsivity set
+    let monitor_power = record_monitor && profile.is_some_and(|p| p.monitor_responsivity_a_per_w.is_some());
+    if let Some(channel) = channels.iter().find(|c| c.port == 0) {
+        return Err(LabError::InvalidParameter(format!(
+            "Invalid port {} on module {}",
+            channel.port, channel.module
+        )));
     }
+    match (axis, profile) {
+        (SweepAxis::Current, Some(profile)) => profile.check_sweep(sweep.start, sweep.stop)?,
+        (SweepAxis::Power, Some(profile)) => profile.check_power_sweep(sweep.start, sweep.stop)?,
+        (SweepAxis::Power, None) => {
+            return Err(LabError::InvalidParameter(
+                "Select a laser profile with the monitor photodiode responsivity and polarity for a power sweep".into(),
+            ));
+        }
+        _ if sweep.start < 0.0 => {
+            return Err(LabError::InvalidParameter(format!("Start {} must not be negative", axis.mode())));
+        }
+        _ => {}
+    }
+    let mut modules: Vec<u8> = channels.iter().map(|c| c.module).collect();
+    modules.sort_unstable();
+    modules.dedup();
+
+    // Safety: fail before zeroing if the interlocks would stop the sweep
+    axis.check_interlocks(cld, sweep.stop)?;
+    cld.check_protection()?;
 
-    // Safety: Ensure TEC is active
-    let tec_on = cld.get_tec_state().map_err(|e| e.to_string())?;
-    if !tec_on {
-        return Err("TEC must be ON before starting the experiment".into());
+    //  laser off at the beginning
+    cld.set_laser_output(false).ok();
+    cld.set_operating_mode(axis.mode())?;
+    match mpm.as_deref_mut() {
+        Some(mpm) => prepare_power_meter(mpm, channels, &modules, settings, control)?,
+        None => info!("No power meter channels, recording the monitor photodiode only"),
     }
 
+    // The diode must sit at its operating temperature before it is driven
+    cld.wait_for_temperature_stable(
+        settings.temperature_tolerance_c,
+        settings.temperature_hold(),
+        settings.temperature_timeout(),
+        &mut |progress| {
+            window.emit("temperature-progress", progress).unwrap_or_else(|e| {
+                error!("Failed to emit temperature-progress: {}", e);
+            });
+            !control.is_cancelled()
+        },
+    )?;
+
+    let mut records = Vec::new();
+    // The guard lives as long as this closure, so the laser is shut off as
+    // soon as the steps end, however they end, before anything is saved
+    let result = (|| -> Result<()> {
+        let mut cld = LaserGuard::new(cld, control);
+        // Start from zero rather than a setpoint left over from earlier use
+        axis.apply(&mut *cld, 0.0)?;
+        cld.set_laser_output(true)?; // turn laser on
+        cld.check_error()?; // the controller may still refuse, e.g. TEC not settled
+        cld.check_protection()?;
+        info!(
+            "Starting {} sweep: {} {unit} to {} {unit}, step {} {unit}, channels {:?}",
+            axis.mode(), sweep.start, sweep.stop, sweep.step, channels
+        );
+
+        let mut setpoint = sweep.start;
+        let mut last_setpoint = None;
+        let mut telemetry_at: Option<Instant> = None;
+
+        while setpoint <= sweep.stop {
+            if control.is_paused() {
+                pause_sweep(&mut *cld, &window, control, axis, last_setpoint)?;
+                if control.wait_while_paused() {
+                    break;
+                }
+                resume_sweep(&mut *cld, &window, control, axis, last_setpoint)?;
+            }
+            if control.is_cancelled() {
+                break;
+            }
+            axis.apply(&mut *cld, setpoint)?;
+            last_setpoint = Some(setpoint);
+            std::thread::sleep(std::time::Duration::from_millis(settings.stabilization_delay_ms));
+
+            let readings = match mpm.as_deref_mut() {
+                Some(mpm) => mpm.read_modules(&modules)?,
+                None => Vec::new(),
+            };
+            // A tripped protection switches the output off; stop instead of recording darkness
+            cld.check_protection()?;
+            let measured_current_ma = cld.measure_current()? * 1000.0;
+            let voltage_v = cld.get_forward_voltage()?;
+            let monitor_current_ma = if record_monitor {
+                Some(cld.measure_photodiode_current()? * 1000.0)
+            } else {
+                None
+            };
+            let monitor_power_dbm = if monitor_power {
+                watts_to_dbm(cld.measure_photodiode_power()?)
+            } else {
+                None
+            };
+            let now = Utc::now().to_rfc3339();
+            let record = |reading: Option<&PowerReading>| MeasurementRecord {
+                timestamp: now.clone(),
+                current_ma: (axis == SweepAxis::Current).then_some(setpoint),
+                power_setpoint_mw: (axis == SweepAxis::Power).then_some(setpoint),
+                measured_current_ma,
+                voltage_v,
+                power_dbm: reading.and_then(|r| r.dbm()),
+                status: reading.map(|r| r.status),
+                module: reading.map(|r| r.module),
+                port: reading.map(|r| r.port),
+                monitor_current_ma,
+                monitor_power_dbm,
+            };
+
+            let mut step_records = Vec::new();
+            if channels.is_empty() {
+                step_records.push(record(None));
+            }
+            for channel in channels {
+                let reading = readings
+                    .iter()
+                    .find(|r| r.module == channel.module && r.port == channel.port)
+                    .ok_or_else(|| {
+                        LabError::ParseError(format!(
+                            "No power reading returned for module {} port {}",
+                            channel.module, channel.port
+                        ))
+                    })?;
+                step_records.push(record(Some(reading)));
+            }
+            for record in step_records {
+                window.emit("sweep-point", &record).unwrap_or_else(|e| {
+                    error!("Failed to emit sweep-point: {}", e);
+                });
+
+                records.push(record);
+            }
+            control.record_point();
+            if telemetry_at.is_none_or(|at| at.elapsed() >= TELEMETRY_INTERVAL) {
+                publish_telemetry(&mut *cld, &window);
+                telemetry_at = Some(Instant::now());
+            }
+            setpoint += sweep.step;
+        }
+        cld.complete();
+        Ok(())
+    })();
+
+    // Keep what was measured before an instrument error, timeout or trip
+    if let Err(e) = result {
+        if !records.is_empty() {
+            match save_results(&records, settings) {
+                Ok(outcome) => warn!(
+                    "Sweep failed after {} points: {}. Partial data saved to: {:?}",
+                    control.points(), e, outcome.data_path
+                ),
+                Err(save_error) => error!("Failed to save partial data of the failed sweep: {}", save_error),
+            }
+        }
+        return Err(e);
+    }
+    let outcome = save_results(&records, settings)?;
+
+    if control.is_cancelled() {
+        warn!("Sweep aborted after {} points. Partial data saved to: {:?}", control.points(), outcome.data_path);
+        return Err(LabError::Aborted);
+    }
+
+    info!("Sweep completed. Data saved to: {:?}, analysis to: {:?}", outcome.data_path, outcome.analysis_path);
+
+    Ok(outcome)
+}
+
+/// Saves the records as CSV and their L-I analysis as JSON next to it
+fn save_results(records: &[MeasurementRecord], settings: &ExperimentSettings) -> Result<SweepOutcome> {
+    let data_path = save_measurements_to_csv(records).map_err(|e| {
+        error!("Failed to save CSV: {}", e);
+        e
+    })?;
+    let analysis = analysis::analyze_sweep(records, &settings.analysis);
+    let analysis_path = save_analysis(&data_path, &analysis).map_err(|e| {
+        error!("Failed to save analysis: {}", e);
+        e
+    })?;
+    Ok(SweepOutcome {
+        data_path,
+        analysis_path,
+        analysis,
+    })
+}
+
+/// Zeroes the power meter, sets it to the laser wavelength and checks that
+/// every channel exists, all before the laser is switched on.
+fn prepare_power_meter<P: PowerMeter>(
+    mpm: &mut P,
+    channels: &[Channel],
+    modules: &[u8],
+    settings: &ExperimentSettings,
+    control: &SweepControl,
+) -> Result<()> {
     // Perform zeroing before starting the sweep to ensure accurate measurements
     info!("Performing zeroing operation before sweep to remove electrical offsets");
     match mpm.perform_zeroing() {
         Ok(_) => info!("Zeroing command sent successfully"),
         Err(e) => {
             error!("Failed to perform zeroing: {}", e);
-            return Err(format!("Failed to perform zeroing: {}", e));
+            return Err(e);
         }
     }
 
-    // Give time for the zeroing operation to complete (3 seconds as per documentation)
-    std::thread::sleep(std::time::Duration::from_secs(3));
+    // Give time for the zeroing operation to complete
+    if control.wait(std::time::Duration::from_millis(settings.zeroing_delay_ms)) {
+        warn!("Sweep aborted during zeroing");
+        return Err(LabError::Aborted);
+    }
     info!("Zeroing completed, proceeding with sweep");
 
-    //  laser off at the beginning
-    cld.set_laser_output(false).ok();
     // ensure mpm210h is at the cld1015 wavelength
-    mpm.set_wavelength(980).map_err(|e| e.to_string())?;
-
-    cld.set_laser_output(true).map_err(|e| e.to_string())?; // turn laser on
-    info!("Starting current sweep: {} mA to {} mA, step {} mA, module {}", start_ma, stop_ma, step_ma, module);
-
-    let mut records = Vec::new();
-    let mut current_ma = start_ma;
-
-    while current_ma <= stop_ma {
-        cld.set_current(current_ma / 1000.0).map_err(|e| e.to_string())?; // convert to A
-        std::thread::sleep(std::time::Duration::from_millis(stabilization_delay_ms));
+    mpm.set_wavelength(settings.wavelength_nm)?;
+    mpm.check_error()?;
 
-        let power = mpm.read_power(module).map_err(|e| e.to_string())?;
-        let now = Utc::now().to_rfc3339();
+    // Catch ports the modules do not have before the laser is switched on
+    let readings = mpm.read_modules(modules)?;
+    if let Some(channel) = channels
+        .iter()
+        .find(|c| !readings.iter().any(|r| r.module == c.module && r.port == c.port))
+    {
+        return Err(LabError::InvalidParameter(format!(
+            "Module {} has no port {}",
+            channel.module, channel.port
+        )));
+    }
+    Ok(())
+}
 
-        let record = MeasurementRecord {
-            timestamp: now.clone(),
-            current_ma,
-            power_dbm: power.clone(),
-            module,
-        };
+/// Optical power in dBm, `None` when there is no light to express in dBm.
+fn watts_to_dbm(watts: f64) -> Option<f64> {
+    (watts > 0.0).then(|| 10.0 * (watts * 1000.0).log10())
+}
 
-        window.emit("sweep-point", &record).unwrap_or_else(|e| {
-            error!("Failed to emit sweep-point: {}", e);
-        });
+/// Park the laser at a safe state while the sweep is paused
+fn pause_sweep<L: LaserDriver>(
+    cld: &mut L,
+    window: &Window,
+    control: &SweepControl,
+    axis: SweepAxis,
+    last_setpoint: Option<f64>,
+) -> Result<()> {
+    info!("Pausing sweep after {} points", control.points());
+    axis.apply(cld, 0.0)?;
+    cld.set_laser_output(false)?;
+    emit_sweep_state(window, "paused", control, axis, last_setpoint);
+    Ok(())
+}
 
-        records.push(record);
-        current_ma += step_ma;
+/// Bring the laser back to where it was before the pause
+fn resume_sweep<L: LaserDriver>(
+    cld: &mut L,
+    window: &Window,
+    control: &SweepControl,
+    axis: SweepAxis,
+    last_setpoint: Option<f64>,
+) -> Result<()> {
+    info!("Resuming sweep at {:?} {}", last_setpoint, axis.unit());
+    cld.set_laser_output(true)?;
+    cld.check_error()?;
+    if let Some(setpoint) = last_setpoint {
+        axis.apply(cld, setpoint)?;
     }
-    cld.set_laser_output(false).ok(); // turn laser off after sweep
+    emit_sweep_state(window, "resumed", control, axis, last_setpoint);
+    Ok(())
+}
 
-    let path = save_measurements_to_csv(&records)
-        .map_err(|e| format!("Failed to save CSV: {}", e))?;
+fn emit_sweep_state(
+    window: &Window,
+    state: &'static str,
+    control: &SweepControl,
+    axis: SweepAxis,
+    setpoint: Option<f64>,
+) {
+    let event = SweepStateEvent {
+        state,
+        points: control.points(),
+        setpoint_ma: setpoint.filter(|_| axis == SweepAxis::Current),
+        setpoint_mw: setpoint.filter(|_| axis == SweepAxis::Power),
+    };
+    window.emit("sweep-state", &event).unwrap_or_else(|e| {
+        error!("Failed to emit sweep-state: {}", e);
+    });
+}
 
-    info!("Sweep completed. Data saved to: {:?}", path);
+/// Publish the laser telemetry in place of the health monitor, which sees the
+/// drivers held by the sweep as busy. The power meter is reported busy too.
+fn publish_telemetry<L: LaserDriver>(cld: &mut L, window: &Window) {
+    let telemetry = DeviceTelemetry {
+        timestamp: Utc::now().to_rfc3339(),
+        cld1015: cld.telemetry(),
+        mpm210h: MeterTelemetry::busy(),
+    };
+    window.emit("device-telemetry", &telemetry).unwrap_or_else(|e| {
+        error!("Failed to emit device-telemetry: {}", e);
+    });
+}
 
+/// Save the L-I analysis as JSON next to the CSV file at `data_path`
+fn save_analysis(data_path: &Path, analysis: &[CurveAnalysis]) -> io::Result<PathBuf> {
+    let stem = data_path.file_stem().unwrap_or_default().to_string_lossy();
+    let path = data_path.with_file_name(format!("{}_analysis.json", stem));
+    let file = File::create(&path)?;
+    serde_json::to_writer_pretty(file, analysis)?;
     Ok(path)
 }
 
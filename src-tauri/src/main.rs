@@ -1,184 +1,755 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 #![allow(unused)]
+mod analysis;
+mod config;
 mod devices;
+mod error;
 mod experiment;
+mod monitor;
+mod safety;
 
-use devices::{CLD1015, MPM210H};
-use tauri::State;
-use std::sync::Mutex;
+use config::{ExperimentSettings, InstrumentConfig, LabConfig, LaserProfile};
+use error::LabError;
+use safety::{SafetyRules, SafetySupervisor};
+use devices::{Connectable, ConnectionState, DiscoveredInstrument, LaserDiodeModel, PowerReading, Protection, SimulatedCLD1015, SimulatedMPM210H, SimulatedMPM210HConfig, CLD1015, MPM210H};
+use experiment::control::SweepControl;
+use experiment::data::{Channel, SweepOutcome};
+use tauri::{Manager, State};
+use std::sync::{Arc, Mutex, TryLockError};
+use std::time::{Duration, Instant};
 use tracing_subscriber::fmt;
+use tracing_subscriber::filter::{filter_fn, LevelFilter};
+use tracing_subscriber::prelude::*;
 use tracing_appender::rolling;
 use tracing::info;
+use tracing::warn;
 use tracing::error;
 
 struct AppState {
-    cld1015: Mutex<CLD1015>,
-    mpm210h: Mutex<MPM210H>,
+    cld1015: Arc<Mutex<CLD1015>>,
+    mpm210h: Arc<Mutex<MPM210H>>,
+    // Control of the sweep running on the background worker, if any
+    experiment: Mutex<Option<Arc<SweepControl>>>,
+    config: Mutex<LabConfig>,
+    // Interlock rules shared by both drivers
+    safety: Arc<SafetySupervisor>,
 }
 
 /*#[tauri::command]
-fn enable_tec(state: State<AppState>) -> Result<(), String> {
-    state.cld1015.lock().unwrap().enable_tec().map_err(|e| e.to_string())
+fn enable_tec(state: State<AppState>) -> Result<(), LabError> {
+    state.cld1015.lock().unwrap().enable_tec()
 }*/
 
-#[tauri::command]
-fn connect_cld1015(state: State<AppState>) -> Result<String, String> {
-    let mut cld = state.cld1015.lock().unwrap();
-    let id = cld.connect().map_err(|e| e.to_string())?;
+/// Connects the CLD1015, applies the TEC and monitor photodiode settings of
+/// the laser `profile` and enables the TEC.
+fn open_cld1015(cld: &mut CLD1015, profile: Option<&LaserProfile>) -> Result<String, LabError> {
+    let id = cld.connect()?;
 
-    state.cld1015.lock().unwrap().enable_tec().map_err(|e| e.to_string())?;
+    if let Some(profile) = profile {
+        apply_profile_settings(cld, profile)?;
+    }
+    cld.enable_tec()?;
 
     Ok(id)
 }
 
-#[tauri::command]
-fn connect_mpm210h(state: State<AppState>) -> Result<String, String> {
-    let mut mpm = state.mpm210h.lock().unwrap();
-    let id = mpm.connect().map_err(|e| e.to_string())?;
+/// Reconnects the CLD1015 after the link was lost. Like `open_cld1015`, but
+/// the TEC is left as the controller reports it: nobody asked for it to be
+/// switched on, so a TEC found off stays off until the user connects again.
+fn reopen_cld1015(cld: &mut CLD1015, profile: Option<&LaserProfile>) -> Result<String, LabError> {
+    let id = cld.connect()?;
+
+    if let Some(profile) = profile {
+        apply_profile_settings(cld, profile)?;
+    }
+    if !cld.get_tec_state()? {
+        warn!(target: "audit", "CLD1015 reconnected with the TEC off; not re-enabling it automatically");
+    }
+
+    Ok(id)
+}
+
+fn apply_profile_settings(cld: &mut CLD1015, profile: &LaserProfile) -> Result<(), LabError> {
+    if let Some(limit_amps) = profile.tec_current_limit_a {
+        cld.set_tec_current_limit(limit_amps)?;
+    }
+    if let Some(responsivity) = profile.monitor_responsivity_a_per_w {
+        cld.set_photodiode_responsivity(responsivity)?;
+    }
+    if let Some(polarity) = profile.monitor_polarity {
+        cld.set_photodiode_polarity(polarity)?;
+    }
+    cld.set_tec_setpoint(profile.tec_setpoint_c)
+}
+
+/// Laser profile in use, cloned so the config lock is not held while a
+/// driver is locked.
+fn active_profile(state: &AppState) -> Option<LaserProfile> {
+    let config = state.config.lock().unwrap();
+    config.active_profile().map(|(_, profile)| profile.clone())
+}
+
+/// Connects the MPM210H and matches it to the laser wavelength. Also used to
+/// reconnect after the link was lost.
+fn open_mpm210h(mpm: &mut MPM210H, wavelength_nm: u32) -> Result<String, LabError> {
+    let id = mpm.connect()?;
 
-    // Enforce wavelength to 980 nm for CLD1015
-    mpm.set_wavelength(980).map_err(|e| e.to_string())?;
+    mpm.set_wavelength(wavelength_nm)?;
 
     Ok(id)
 }
 
+/// Runs `command` on a blocking worker with `driver` locked, so the main
+/// thread never waits for a driver. While an experiment runs, a driver held
+/// by the sweep fails the command with `LabError::Busy` instead of waiting
+/// for the sweep to end.
+async fn with_driver<D, T, F>(state: &AppState, device: &str, driver: &Arc<Mutex<D>>, command: F) -> Result<T, LabError>
+where
+    D: Send + 'static,
+    T: Send + 'static,
+    F: FnOnce(&mut D) -> Result<T, LabError> + Send + 'static,
+{
+    let running = state.experiment.lock().unwrap().is_some();
+    let driver = driver.clone();
+    let device = device.to_string();
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut driver = match driver.try_lock() {
+            Ok(driver) => driver,
+            Err(_) if running => {
+                info!("{} is in use by the running experiment", device);
+                return Err(LabError::Busy { device });
+            }
+            // Otherwise only held briefly, by the health monitor or a reconnect
+            Err(_) => driver.lock().unwrap(),
+        };
+        command(&mut driver)
+    })
+    .await
+    .map_err(|e| LabError::Io(std::io::Error::other(e.to_string())))?
+}
+
+#[tauri::command]
+async fn connect_cld1015(state: State<'_, AppState>) -> Result<String, LabError> {
+    let profile = active_profile(&state);
+    with_driver(&state, "CLD1015", &state.cld1015, move |cld| open_cld1015(cld, profile.as_ref())).await
+}
+
+#[tauri::command]
+async fn connect_mpm210h(state: State<'_, AppState>) -> Result<String, LabError> {
+    let wavelength_nm = state.config.lock().unwrap().experiment_settings().wavelength_nm;
+    with_driver(&state, "MPM210H", &state.mpm210h, move |mpm| open_mpm210h(mpm, wavelength_nm)).await
+}
+
+/// Switches the laser off and closes the link to the CLD1015.
+#[tauri::command]
+async fn disconnect_cld1015(state: State<'_, AppState>) -> Result<(), LabError> {
+    if state.experiment.lock().unwrap().is_some() {
+        return Err(LabError::InvalidParameter("Cannot disconnect while an experiment is running".into()));
+    }
+    with_driver(&state, "CLD1015", &state.cld1015, |cld| cld.disconnect()).await
+}
+
 #[tauri::command]
-fn is_cld1015_connected(state: State<AppState>) -> bool {
-    state.cld1015.lock().unwrap().is_connected()
+async fn disconnect_mpm210h(state: State<'_, AppState>) -> Result<(), LabError> {
+    if state.experiment.lock().unwrap().is_some() {
+        return Err(LabError::InvalidParameter("Cannot disconnect while an experiment is running".into()));
+    }
+    with_driver(&state, "MPM210H", &state.mpm210h, |mpm| {
+        mpm.disconnect();
+        Ok(())
+    })
+    .await
+}
+
+/// Checks that the CLD1015 still answers, not just that it was connected.
+#[tauri::command]
+async fn is_cld1015_connected(state: State<'_, AppState>) -> Result<bool, LabError> {
+    with_driver(&state, "CLD1015", &state.cld1015, |cld| Ok(cld.is_connected() && cld.ping().is_ok())).await
 }
 
 #[tauri::command]
-fn is_mpm210h_connected(state: State<AppState>) -> bool {
-    state.mpm210h.lock().unwrap().is_connected()
+async fn is_mpm210h_connected(state: State<'_, AppState>) -> Result<bool, LabError> {
+    with_driver(&state, "MPM210H", &state.mpm210h, |mpm| Ok(mpm.is_connected() && mpm.ping().is_ok())).await
 }
 
 #[tauri::command]
-fn get_tec_state(state: State<AppState>) -> Result<bool, String> {
-    state.cld1015.lock().unwrap().get_tec_state().map_err(|e| e.to_string())
+async fn get_tec_state(state: State<'_, AppState>) -> Result<bool, LabError> {
+    with_driver(&state, "CLD1015", &state.cld1015, |cld| cld.get_tec_state()).await
 }
 
 /*#[tauri::command]
-fn set_cld1015_current(state: State<AppState>, current_ma: f64) -> Result<(), String> {
+fn set_cld1015_current(state: State<AppState>, current_ma: f64) -> Result<(), LabError> {
     // Convert mA to A (since the device expects amps)
     let current_amps = current_ma / 1000.0;
-    state.cld1015.lock().unwrap().set_current(current_amps).map_err(|e| e.to_string())
+    state.cld1015.lock().unwrap().set_current(current_amps)
 }*/
 
 /*#[tauri::command]
-fn get_cld1015_current(state: State<AppState>) -> Result<f64, String> {
+fn get_cld1015_current(state: State<AppState>) -> Result<f64, LabError> {
     // Return current in mA
     state.cld1015.lock().unwrap().get_current()
         .map(|a| a * 1000.0) // Convert from A to mA
-        .map_err(|e| e.to_string())
+        
 }*/
 
 /*#[tauri::command]
-fn set_cld1015_laser_output(state: State<AppState>, enabled: bool) -> Result<(), String> {
-    let mut cld = state.cld1015.lock().unwrap();
-    if enabled && !cld.get_tec_state().map_err(|e| e.to_string())? {
-        return Err("Cannot enable laser: TEC is OFF".into());
-    }
-    cld.set_laser_output(enabled).map_err(|e| e.to_string())
+fn set_cld1015_laser_output(state: State<AppState>, enabled: bool) -> Result<(), LabError> {
+    state.cld1015.lock().unwrap().set_laser_output(enabled)
 }*/
 
+/// Hardware protections of the CLD1015 that are currently tripped.
 #[tauri::command]
-fn get_cld1015_error(state: State<AppState>) -> Result<String, String> {
-    state.cld1015.lock().unwrap().get_error().map_err(|e| {
-        error!("Failed to read CLD1015 error queue: {}", e);
-        e.to_string()
+async fn get_cld1015_protection(state: State<'_, AppState>) -> Result<Vec<Protection>, LabError> {
+    with_driver(&state, "CLD1015", &state.cld1015, |cld| cld.tripped_protections()).await
+}
+
+#[tauri::command]
+async fn get_cld1015_error(state: State<'_, AppState>) -> Result<String, LabError> {
+    with_driver(&state, "CLD1015", &state.cld1015, |cld| {
+        cld.get_error().map_err(|e| {
+            error!("Failed to read CLD1015 error queue: {}", e);
+            e
+        })
     })
+    .await
 }
 
 #[tauri::command]
-fn clear_cld1015_error_queue(state: State<AppState>) -> Result<Vec<String>, String> {
-    state.cld1015.lock().unwrap()
-        .clear_error_queue()
-        .map_err(|e| {
+async fn clear_cld1015_error_queue(state: State<'_, AppState>) -> Result<Vec<String>, LabError> {
+    with_driver(&state, "CLD1015", &state.cld1015, |cld| {
+        cld.clear_error_queue().map_err(|e| {
             error!("Failed to clear CLD1015 error queue: {}", e);
-            e.to_string()
+            e
         })
+    })
+    .await
 }
 
 #[tauri::command]
-fn get_mpm210h_modules(state: State<AppState>) -> Result<String, String> {
-    state.mpm210h.lock().unwrap().get_recognized_modules().map_err(|e| e.to_string())
+async fn get_mpm210h_modules(state: State<'_, AppState>) -> Result<String, LabError> {
+    with_driver(&state, "MPM210H", &state.mpm210h, |mpm| mpm.get_recognized_modules()).await
 }
 
 /*#[tauri::command]
-fn read_mpm210h_power(state: State<AppState>, module: u8) -> Result<String, String> {
-    state.mpm210h.lock().unwrap().read_power(module).map_err(|e| e.to_string())
+fn read_mpm210h_power(state: State<AppState>, module: u8) -> Result<Vec<PowerReading>, LabError> {
+    state.mpm210h.lock().unwrap().read_power(module)
 }*/
 
 #[tauri::command]
-fn get_mpm210h_wavelength(state: State<AppState>) -> Result<String, String> {
-    state.mpm210h.lock().unwrap().get_wavelength().map_err(|e| e.to_string())
+async fn get_mpm210h_wavelength(state: State<'_, AppState>) -> Result<String, LabError> {
+    with_driver(&state, "MPM210H", &state.mpm210h, |mpm| mpm.get_wavelength()).await
 }
 
 /*#[tauri::command]
-fn set_mpm210h_wavelength(state: State<AppState>, wavelength: u32) -> Result<(), String> {
-    state.mpm210h.lock().unwrap().set_wavelength(wavelength).map_err(|e| e.to_string())
+fn set_mpm210h_wavelength(state: State<AppState>, wavelength: u32) -> Result<(), LabError> {
+    state.mpm210h.lock().unwrap().set_wavelength(wavelength)
 }*/
 
 #[tauri::command]
-fn get_mpm210h_error(state: State<AppState>) -> Result<String, String> {
-    state.mpm210h.lock().unwrap().get_error().map_err(|e| {
-        error!("Failed to read MPM210H error queue: {}", e);
-        e.to_string()
+async fn get_mpm210h_error(state: State<'_, AppState>) -> Result<String, LabError> {
+    with_driver(&state, "MPM210H", &state.mpm210h, |mpm| {
+        mpm.get_error().map_err(|e| {
+            error!("Failed to read MPM210H error queue: {}", e);
+            e
+        })
     })
+    .await
 }
 
 #[tauri::command]
-fn clear_mpm210h_error_queue(state: State<AppState>) -> Result<Vec<String>, String> {
-    state.mpm210h.lock().unwrap().clear_error_queue().map_err(|e| {
-        error!("Failed to clear MPM210H error queue: {}", e);
-        e.to_string()
+async fn clear_mpm210h_error_queue(state: State<'_, AppState>) -> Result<Vec<String>, LabError> {
+    with_driver(&state, "MPM210H", &state.mpm210h, |mpm| {
+        mpm.clear_error_queue().map_err(|e| {
+            error!("Failed to clear MPM210H error queue: {}", e);
+            e
+        })
     })
+    .await
 }
 
+/// Runs a current sweep on the power meter `channels`. Without channels only
+/// the CLD1015 monitor photodiode is recorded and the power meter is not used.
 #[tauri::command]
-fn run_current_sweep(
+async fn run_current_sweep(
     window: tauri::Window,
-    state: State<AppState>,
-    module: u8,
+    state: State<'_, AppState>,
+    channels: Vec<Channel>,
     start_ma: f64,
     stop_ma: f64,
     step_ma: f64,
-) -> Result<String, String> {
-    let path = experiment::run_current_sweep_with_live_plot(
-        &mut state.cld1015.lock().unwrap(),
-        &mut state.mpm210h.lock().unwrap(),
-        module,
-        start_ma,
-        stop_ma,
-        step_ma,
-        20, // ms stabilization enough for cld1015 and mpm210h
-        window,
-    )?;
+) -> Result<SweepOutcome, LabError> {
+    run_experiment(window, &state, channels, move |cld, mpm, channels, settings, profile, window, control| {
+        experiment::run_current_sweep_with_live_plot(
+            cld, mpm, channels, start_ma, stop_ma, step_ma, settings, profile, window, control,
+        )
+    })
+    .await
+}
 
-    Ok(path.to_string_lossy().into())
+/// Runs a power sweep in constant-power mode, otherwise like `run_current_sweep`.
+#[tauri::command]
+async fn run_power_sweep(
+    window: tauri::Window,
+    state: State<'_, AppState>,
+    channels: Vec<Channel>,
+    start_mw: f64,
+    stop_mw: f64,
+    step_mw: f64,
+) -> Result<SweepOutcome, LabError> {
+    run_experiment(window, &state, channels, move |cld, mpm, channels, settings, profile, window, control| {
+        experiment::run_power_sweep_with_live_plot(
+            cld, mpm, channels, start_mw, stop_mw, step_mw, settings, profile, window, control,
+        )
+    })
+    .await
+}
+
+/// Registers `sweep` as the running experiment and runs it on a blocking
+/// worker, so the command thread stays free for `abort_experiment`. The
+/// power meter is only locked when there are `channels` to read.
+async fn run_experiment<F>(
+    window: tauri::Window,
+    state: &AppState,
+    channels: Vec<Channel>,
+    sweep: F,
+) -> Result<SweepOutcome, LabError>
+where
+    F: FnOnce(
+            &mut CLD1015,
+            Option<&mut MPM210H>,
+            &[Channel],
+            &ExperimentSettings,
+            Option<&LaserProfile>,
+            tauri::Window,
+            &SweepControl,
+        ) -> Result<SweepOutcome, LabError>
+        + Send
+        + 'static,
+{
+    let control = Arc::new(SweepControl::new());
+    {
+        let mut running = state.experiment.lock().unwrap();
+        if running.is_some() {
+            return Err(LabError::InvalidParameter("An experiment is already running".into()));
+        }
+        *running = Some(control.clone());
+    }
+
+    let cld1015 = state.cld1015.clone();
+    let mpm210h = state.mpm210h.clone();
+    let (settings, profile) = {
+        let config = state.config.lock().unwrap();
+        (config.experiment_settings(), config.active_profile().map(|(_, profile)| profile.clone()))
+    };
+    let worker_control = control.clone();
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        let mut mpm = (!channels.is_empty()).then(|| mpm210h.lock().unwrap());
+        let result = sweep(
+            &mut cld1015.lock().unwrap(),
+            mpm.as_deref_mut(),
+            &channels,
+            &settings,
+            profile.as_ref(),
+            window,
+            &worker_control,
+        );
+        worker_control.finish();
+        result
+    })
+    .await;
+
+    state.experiment.lock().unwrap().take();
+    result.map_err(|e| {
+        error!("Sweep worker failed: {}", e);
+        control.finish();
+        // A panic poisons the driver locks; the laser guard has already
+        // shut the laser off, so the drivers remain usable
+        state.cld1015.clear_poison();
+        state.mpm210h.clear_poison();
+        LabError::Io(std::io::Error::other(e.to_string()))
+    })?
+}
+
+/// Stops the running sweep within one step and makes sure the laser is off.
+/// Returns the number of points collected before the stop.
+#[tauri::command]
+async fn abort_experiment(state: State<'_, AppState>) -> Result<usize, LabError> {
+    let control = state.experiment.lock().unwrap().clone();
+    let cld1015 = state.cld1015.clone();
+
+    tauri::async_runtime::spawn_blocking(move || {
+        let points = match control {
+            Some(control) => {
+                warn!("Aborting experiment");
+                control.cancel();
+                if !control.wait_finished(Duration::from_secs(10)) {
+                    warn!("Sweep did not stop within 10 s of the abort request");
+                }
+                control.points()
+            }
+            None => {
+                info!("Abort requested with no experiment running");
+                0
+            }
+        };
+
+        // The sweep is already cancelled; a controller that is not connected
+        // has no output to switch off and must not fail the abort
+        match cld1015.lock().unwrap().set_laser_output(false) {
+            Ok(()) => info!("Experiment aborted after {} points, laser output off", points),
+            Err(e @ LabError::NotConnected { .. }) => {
+                warn!("Experiment aborted after {} points; laser output not switched off: {}", points, e)
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(points)
+    })
+    .await
+    .map_err(|e| LabError::Io(std::io::Error::other(e.to_string())))?
+}
+
+/// Hard stop for emergencies: switches the laser output off without ramping
+/// the current down, and cancels the running sweep, which does the same.
+#[tauri::command]
+async fn emergency_stop(state: State<'_, AppState>) -> Result<(), LabError> {
+    let control = state.experiment.lock().unwrap().clone();
+    let cld1015 = state.cld1015.clone();
+
+    tauri::async_runtime::spawn_blocking(move || {
+        if let Some(control) = control {
+            warn!("Emergency stop of the running experiment");
+            control.emergency_stop();
+            if !control.wait_finished(Duration::from_secs(10)) {
+                warn!("Sweep did not stop within 10 s of the emergency stop");
+            }
+        }
+        cld1015.lock().unwrap().emergency_off()
+    })
+    .await
+    .map_err(|e| LabError::Io(std::io::Error::other(e.to_string())))?
+}
+
+fn running_experiment(state: &AppState) -> Result<Arc<SweepControl>, LabError> {
+    state
+        .experiment
+        .lock()
+        .unwrap()
+        .clone()
+        .ok_or_else(|| LabError::InvalidParameter("No experiment is running".into()))
+}
+
+/// Pauses the running sweep before its next step; the laser is parked at
+/// zero current with the output off until `resume_experiment`.
+#[tauri::command]
+fn pause_experiment(state: State<AppState>) -> Result<(), LabError> {
+    info!("Pause requested");
+    running_experiment(&state)?.pause();
+    Ok(())
+}
+
+#[tauri::command]
+fn resume_experiment(state: State<AppState>) -> Result<(), LabError> {
+    info!("Resume requested");
+    running_experiment(&state)?.resume();
+    Ok(())
+}
+
+fn build_cld1015(config: &InstrumentConfig, safety: &Arc<SafetySupervisor>) -> CLD1015 {
+    let mut cld = CLD1015::with_transport(config.transport_spec());
+    cld.set_safety(safety.clone());
+    for (header, timeout) in config.command_timeouts() {
+        cld.set_command_timeout(header, timeout);
+    }
+    cld
+}
+
+fn build_mpm210h(config: &InstrumentConfig, safety: &Arc<SafetySupervisor>) -> MPM210H {
+    let mut mpm = MPM210H::with_transport(config.transport_spec());
+    mpm.set_safety(safety.clone());
+    for (header, timeout) in config.command_timeouts() {
+        mpm.set_command_timeout(header, timeout);
+    }
+    mpm
+}
+
+/// Lists the instruments reachable through VISA or on the configured hosts,
+/// with the driver suggested for each.
+#[tauri::command]
+async fn discover_instruments(state: State<'_, AppState>) -> Result<Vec<DiscoveredInstrument>, LabError> {
+    if state.experiment.lock().unwrap().is_some() {
+        return Err(LabError::InvalidParameter("Cannot discover instruments while an experiment is running".into()));
+    }
+    let discovery = state.config.lock().unwrap().discovery.clone();
+    let cld1015 = state.cld1015.clone();
+    let mpm210h = state.mpm210h.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        // Links the drivers hold, or are about to reconnect, are not probed
+        let mut held = Vec::new();
+        {
+            let cld = cld1015.lock().unwrap();
+            if cld.connection_state() != ConnectionState::Disconnected {
+                held.push(cld.transport_spec().clone());
+            }
+        }
+        {
+            let mpm = mpm210h.lock().unwrap();
+            if mpm.connection_state() != ConnectionState::Disconnected {
+                held.push(mpm.transport_spec().clone());
+            }
+        }
+        devices::discover_instruments(&discovery, &held)
+    })
+    .await
+        .map_err(|e| LabError::Io(std::io::Error::other(e.to_string())))?
+}
+
+#[tauri::command]
+fn get_lab_config(state: State<AppState>) -> LabConfig {
+    state.config.lock().unwrap().clone()
+}
+
+/// Hands new rules to the safety supervisor and programs the matching
+/// current limit into a connected CLD1015.
+async fn apply_safety_rules(state: &AppState, rules: SafetyRules) -> Result<(), LabError> {
+    state.safety.set_rules(rules);
+    with_driver(state, "CLD1015", &state.cld1015, |cld| {
+        if cld.is_connected() {
+            cld.program_current_limit()?;
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Re-reads the lab config. Instruments whose settings changed are replaced by
+/// a fresh, disconnected driver (laser output off first) and must be connected
+/// again.
+#[tauri::command]
+async fn reload_lab_config(state: State<'_, AppState>) -> Result<LabConfig, LabError> {
+    if state.experiment.lock().unwrap().is_some() {
+        return Err(LabError::InvalidParameter("Cannot reload the lab config while an experiment is running".into()));
+    }
+    let mut new_config = LabConfig::load()?;
+    // Not held while the drivers are locked: the MPM210H watchdog reads the
+    // config with the driver locked
+    let config = state.config.lock().unwrap().clone();
+
+    if new_config.simulate != config.simulate {
+        warn!("Switching simulation on or off requires a restart, keeping simulate = {}", config.simulate);
+        new_config.simulate = config.simulate;
+    }
+    if !config.simulate {
+        if new_config.instruments.cld1015 != config.instruments.cld1015 {
+            info!("CLD1015 settings changed, replacing driver");
+            let instrument = new_config.instruments.cld1015.clone();
+            let safety = state.safety.clone();
+            with_driver(&state, "CLD1015", &state.cld1015, move |cld| {
+                if cld.is_connected() {
+                    cld.set_laser_output(false).ok();
+                }
+                *cld = build_cld1015(&instrument, &safety);
+                Ok(())
+            })
+            .await?;
+        }
+        if new_config.instruments.mpm210h != config.instruments.mpm210h {
+            info!("MPM210H settings changed, replacing driver");
+            let instrument = new_config.instruments.mpm210h.clone();
+            let safety = state.safety.clone();
+            with_driver(&state, "MPM210H", &state.mpm210h, move |mpm| {
+                *mpm = build_mpm210h(&instrument, &safety);
+                Ok(())
+            })
+            .await?;
+        }
+    }
+    *state.config.lock().unwrap() = new_config.clone();
+    if new_config.safety_rules() != config.safety_rules() {
+        apply_safety_rules(&state, new_config.safety_rules()).await?;
+    }
+    Ok(new_config)
+}
+
+/// Switches to the laser profile `name` (or to none) after a diode swap. The
+/// laser is switched off, the profile's limits, TEC and photodiode settings take effect
+/// immediately and a connected power meter is set to its wavelength. The choice is not written
+/// back to the config file.
+#[tauri::command]
+async fn select_laser_profile(state: State<'_, AppState>, name: Option<String>) -> Result<LabConfig, LabError> {
+    if state.experiment.lock().unwrap().is_some() {
+        return Err(LabError::InvalidParameter("Cannot change the laser profile while an experiment is running".into()));
+    }
+    let mut new_config = state.config.lock().unwrap().clone();
+    new_config.select_profile(name)?;
+    info!("Selecting laser profile {:?}", new_config.active_profile);
+
+    with_driver(&state, "CLD1015", &state.cld1015, |cld| {
+        if cld.is_connected() {
+            cld.set_laser_output(false)?;
+        }
+        Ok(())
+    })
+    .await?;
+    *state.config.lock().unwrap() = new_config.clone();
+    apply_safety_rules(&state, new_config.safety_rules()).await?;
+    if let Some((_, profile)) = new_config.active_profile() {
+        let profile = profile.clone();
+        with_driver(&state, "CLD1015", &state.cld1015, move |cld| {
+            if cld.is_connected() {
+                apply_profile_settings(cld, &profile)?;
+            }
+            Ok(())
+        })
+        .await?;
+    }
+    let wavelength_nm = new_config.experiment_settings().wavelength_nm;
+    with_driver(&state, "MPM210H", &state.mpm210h, move |mpm| {
+        if mpm.is_connected() {
+            mpm.set_wavelength(wavelength_nm)?;
+        }
+        Ok(())
+    })
+    .await?;
+    Ok(new_config)
+}
+
+/// Called when the application exits: stops a running sweep (its laser guard
+/// switches the laser off) and makes sure the laser output is off.
+fn shutdown_laser_on_exit(state: &AppState) {
+    if let Some(control) = state.experiment.lock().unwrap().clone() {
+        warn!("Application exiting during an experiment, aborting it");
+        control.cancel();
+        if !control.wait_finished(Duration::from_secs(10)) {
+            warn!("Sweep did not stop within 10 s of exit");
+        }
+    }
+    // A sweep stuck in I/O may still hold the driver; exit rather than hang
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let mut cld = loop {
+        match state.cld1015.try_lock() {
+            Ok(cld) => break cld,
+            Err(TryLockError::Poisoned(e)) => break e.into_inner(),
+            Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            Err(TryLockError::WouldBlock) => {
+                error!(target: "audit", "Exit hook could not lock the CLD1015, laser output state unknown");
+                return;
+            }
+        }
+    };
+    if cld.is_connected() {
+        match cld.set_laser_output(false) {
+            Ok(()) => info!(target: "audit", "Exit hook: laser output off"),
+            Err(e) => error!(target: "audit", "Exit hook could not switch the laser off: {}", e),
+        }
+    }
 }
 
 fn main() {
     // Rotate daily into "logs/app.log.YYYY-MM-DD"
     let file_appender = rolling::daily("logs", "app.log");
     let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
+    // Laser shutdowns and other safety actions (target "audit") are also
+    // kept on their own in "logs/audit.log.YYYY-MM-DD"
+    let audit_appender = rolling::daily("logs", "audit.log");
+    let (audit_writer, _audit_guard) = tracing_appender::non_blocking(audit_appender);
     // Initialize global logger
-    fmt()
-        .with_writer(non_blocking)
-        .with_ansi(false) // Disable ANSI if viewing in plain file
-        .with_level(true)
+    tracing_subscriber::registry()
+        .with(
+            fmt::layer()
+                .with_writer(non_blocking)
+                .with_ansi(false) // Disable ANSI if viewing in plain file
+                .with_level(true)
+                .with_filter(LevelFilter::INFO),
+        )
+        .with(
+            fmt::layer()
+                .with_writer(audit_writer)
+                .with_ansi(false)
+                .with_level(true)
+                .with_filter(filter_fn(|metadata| metadata.target() == "audit")),
+        )
         .init();
+
+    let mut config = LabConfig::load().unwrap_or_else(|e| {
+        error!("Failed to load lab config, using defaults: {}", e);
+        LabConfig::default()
+    });
+    // OPTICAL_LAB_SIMULATE forces simulation regardless of the config file
+    if std::env::var_os("OPTICAL_LAB_SIMULATE").is_some() {
+        config.simulate = true;
+    }
+
+    if let Some((name, _)) = config.active_profile() {
+        info!("Active laser profile: {}", name);
+    }
+    let safety = Arc::new(SafetySupervisor::new(config.safety_rules()));
+
+    // The simulated power meter sees the output of the simulated laser
+    let mut _mpm_simulator = None;
+    let (cld1015, mpm210h) = if config.simulate {
+        info!("Simulation enabled, using simulated CLD1015 and MPM210H");
+        let laser = SimulatedCLD1015::new(LaserDiodeModel::default());
+        let source = laser.clone();
+        let simulator_config = SimulatedMPM210HConfig {
+            power_source: std::sync::Arc::new(move |_, _| source.optical_power_w()),
+            ..Default::default()
+        };
+        let simulator = SimulatedMPM210H::start(0, simulator_config).expect("failed to start simulated MPM210H");
+        let mut mpm = MPM210H::new("127.0.0.1", simulator.local_addr().port());
+        mpm.set_safety(safety.clone());
+        _mpm_simulator = Some(simulator);
+        let mut cld = CLD1015::simulated(laser);
+        cld.set_safety(safety.clone());
+        (cld, mpm)
+    } else {
+        (
+            build_cld1015(&config.instruments.cld1015, &safety),
+            build_mpm210h(&config.instruments.mpm210h, &safety),
+        )
+    };
+
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
+        .setup(|app| {
+            // Reconnect instruments whose link fails while connected
+            let state = app.state::<AppState>();
+            let handle = app.handle().clone();
+            devices::connection::spawn_watchdog("CLD1015", state.cld1015.clone(), app.handle().clone(), move |cld| {
+                let profile = active_profile(&handle.state::<AppState>());
+                reopen_cld1015(cld, profile.as_ref())
+            });
+            let handle = app.handle().clone();
+            devices::connection::spawn_watchdog(
+                "MPM210H",
+                state.mpm210h.clone(),
+                app.handle().clone(),
+                move |mpm| {
+                    let wavelength_nm = handle.state::<AppState>().config.lock().unwrap().experiment_settings().wavelength_nm;
+                    open_mpm210h(mpm, wavelength_nm)
+                },
+            );
+            let handle = app.handle().clone();
+            monitor::spawn_health_monitor(state.cld1015.clone(), state.mpm210h.clone(), app.handle().clone(), move || {
+                handle.state::<AppState>().config.lock().unwrap().monitor.interval()
+            });
+            Ok(())
+        })
         .manage(AppState {
-            cld1015: Mutex::new(CLD1015::new("USB0::4883::32847::M01053290::0::INSTR")),
-            mpm210h: Mutex::new(MPM210H::new("192.168.1.161", 5000)),
+            cld1015: Arc::new(Mutex::new(cld1015)),
+            mpm210h: Arc::new(Mutex::new(mpm210h)),
+            experiment: Mutex::new(None),
+            config: Mutex::new(config),
+            safety,
         })
         .invoke_handler(tauri::generate_handler![
             //enable_tec,
             connect_cld1015,
             connect_mpm210h,
+            disconnect_cld1015,
+            disconnect_mpm210h,
             is_cld1015_connected,
             is_mpm210h_connected,
             get_tec_state,
@@ -191,10 +762,25 @@ fn main() {
             //set_mpm210h_wavelength,
             get_cld1015_error,
             clear_cld1015_error_queue,
+            get_cld1015_protection,
             get_mpm210h_error,
             clear_mpm210h_error_queue,
             run_current_sweep,
+            run_power_sweep,
+            abort_experiment,
+            emergency_stop,
+            pause_experiment,
+            resume_experiment,
+            get_lab_config,
+            reload_lab_config,
+            select_laser_profile,
+            discover_instruments,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            if let tauri::RunEvent::Exit = event {
+                shutdown_laser_on_exit(&app.state::<AppState>());
+            }
+        });
 }
@@ -0,0 +1,178 @@
+use chrono::Utc;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter};
+use tracing::{error, info};
+
+use crate::devices::{Connectable, ConnectionState, PowerUnit, Protection, CLD1015, MPM210H};
+use crate::error::Result;
+
+/// Payload of the `device-telemetry` event.
+#[derive(Serialize, Clone, Debug)]
+pub struct DeviceTelemetry {
+    pub timestamp: String, // UTC ISO timestamp
+    pub cld1015: LaserTelemetry,
+    pub mpm210h: MeterTelemetry,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LaserTelemetry {
+    /// `None` when busy
+    pub state: Option<ConnectionState>,
+    /// The driver was in use (e.g. by a sweep) and was not sampled
+    pub busy: bool,
+    pub tec_on: Option<bool>,
+    pub laser_on: Option<bool>,
+    #[serde(rename = "setpoint_mA")]
+    pub setpoint_ma: Option<f64>,
+    pub temperature_c: Option<f64>,
+    /// Thermistor resistance the temperature is derived from, in Ω
+    pub thermistor_ohms: Option<f64>,
+    pub tec_setpoint_c: Option<f64>,
+    /// Hardware protections that have switched the output off
+    pub tripped: Vec<Protection>,
+    /// Whether the error queue holds entries, from the status byte. The queue
+    /// itself is not read, so the entries stay for whoever checks it
+    pub error_pending: Option<bool>,
+    /// Why sampling stopped early, if it did
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct MeterTelemetry {
+    pub state: Option<ConnectionState>,
+    pub busy: bool,
+    pub wavelength_nm: Option<f64>,
+    pub unit: Option<PowerUnit>,
+    /// `IDIS?` reply, one flag per slot
+    pub modules: Option<String>,
+    pub error_pending: Option<bool>,
+    pub error: Option<String>,
+}
+
+impl LaserTelemetry {
+    fn empty() -> Self {
+        LaserTelemetry {
+            state: None,
+            busy: false,
+            tec_on: None,
+            laser_on: None,
+            setpoint_ma: None,
+            temperature_c: None,
+            thermistor_ohms: None,
+            tec_setpoint_c: None,
+            tripped: Vec::new(),
+            error_pending: None,
+            error: None,
+        }
+    }
+}
+
+impl MeterTelemetry {
+    /// A meter that is in use and was not sampled.
+    pub fn busy() -> Self {
+        MeterTelemetry {
+            state: None,
+            busy: true,
+            wavelength_nm: None,
+            unit: None,
+            modules: None,
+            error_pending: None,
+            error: None,
+        }
+    }
+}
+
+/// Starts a thread that samples both instruments every `interval()` and
+/// emits a `device-telemetry` event. `interval` is re-read every cycle so a
+/// reloaded config takes effect; `None` pauses the monitor.
+///
+/// Drivers are only `try_lock`ed and never held at the same time, so the
+/// monitor cannot deadlock with a sweep or a command; a driver that is in use
+/// is reported as `busy` for that cycle. A running sweep publishes the laser
+/// telemetry itself.
+pub fn spawn_health_monitor<F>(cld: Arc<Mutex<CLD1015>>, mpm: Arc<Mutex<MPM210H>>, app: AppHandle, interval: F)
+where
+    F: Fn() -> Option<Duration> + Send + 'static,
+{
+    thread::spawn(move || {
+        info!("Health monitor started");
+        loop {
+            let Some(period) = interval() else {
+                thread::sleep(Duration::from_secs(1));
+                continue;
+            };
+            let telemetry = DeviceTelemetry {
+                timestamp: Utc::now().to_rfc3339(),
+                cld1015: sample_laser(&cld),
+                mpm210h: sample_meter(&mpm),
+            };
+            app.emit("device-telemetry", &telemetry).unwrap_or_else(|e| {
+                error!("Failed to emit device-telemetry: {}", e);
+            });
+            thread::sleep(period);
+        }
+    });
+}
+
+fn sample_laser(cld: &Mutex<CLD1015>) -> LaserTelemetry {
+    match cld.try_lock() {
+        Ok(mut cld) => laser_telemetry(&mut cld),
+        Err(_) => LaserTelemetry {
+            busy: true,
+            ..LaserTelemetry::empty()
+        },
+    }
+}
+
+/// Samples a CLD1015 the caller already holds, such as a sweep publishing
+/// telemetry between its steps.
+pub fn laser_telemetry(cld: &mut CLD1015) -> LaserTelemetry {
+    let mut telemetry = LaserTelemetry::empty();
+    telemetry.state = Some(cld.connection_state());
+    if telemetry.state == Some(ConnectionState::Connected) {
+        if let Err(e) = read_laser(cld, &mut telemetry) {
+            telemetry.error = Some(e.to_string());
+        }
+    }
+    telemetry
+}
+
+fn read_laser(cld: &mut CLD1015, telemetry: &mut LaserTelemetry) -> Result<()> {
+    telemetry.tec_on = Some(cld.get_tec_state()?);
+    telemetry.laser_on = Some(cld.get_laser_output()?);
+    telemetry.setpoint_ma = Some(cld.get_current()? * 1000.0);
+    telemetry.temperature_c = Some(cld.get_temperature()?);
+    telemetry.thermistor_ohms = Some(cld.get_thermistor_resistance()?);
+    telemetry.tec_setpoint_c = Some(cld.get_tec_setpoint()?);
+    telemetry.tripped = cld.tripped_protections()?;
+    telemetry.error_pending = Some(cld.error_pending()?);
+    Ok(())
+}
+
+fn sample_meter(mpm: &Mutex<MPM210H>) -> MeterTelemetry {
+    let Ok(mut mpm) = mpm.try_lock() else {
+        return MeterTelemetry::busy();
+    };
+    let mut telemetry = MeterTelemetry {
+        state: Some(mpm.connection_state()),
+        busy: false,
+        ..MeterTelemetry::busy()
+    };
+    if telemetry.state == Some(ConnectionState::Connected) {
+        if let Err(e) = read_meter(&mut mpm, &mut telemetry) {
+            telemetry.error = Some(e.to_string());
+        }
+    }
+    telemetry
+}
+
+fn read_meter(mpm: &mut MPM210H, telemetry: &mut MeterTelemetry) -> Result<()> {
+    telemetry.wavelength_nm = mpm.get_wavelength()?.trim().parse().ok();
+    telemetry.unit = Some(mpm.get_unit()?);
+    telemetry.modules = Some(mpm.get_recognized_modules()?);
+    telemetry.error_pending = Some(mpm.error_pending()?);
+    Ok(())
+}
@@ -0,0 +1,276 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::devices::{PowerReading, ReadingStatus};
+use crate::error::{LabError, Result};
+
+/// Interlock limits enforced on every laser and power meter command. Loaded
+/// from the `safety` section of `lab_config.json`; a `None` limit disables
+/// that rule.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct SafetyRules {
+    /// Highest laser current that may be set
+    pub max_current_ma: f64,
+    /// Fastest allowed change of the laser current; larger changes are ramped
+    pub max_slew_ma_per_ms: Option<f64>,
+    /// Fastest allowed change of the constant-power setpoint, ramped the same way
+    pub max_power_slew_mw_per_ms: Option<f64>,
+    /// Highest optical power any power meter port may read back
+    pub max_optical_power_dbm: Option<f64>,
+    /// The TEC must be on before the laser output is enabled
+    pub require_tec: bool,
+    /// Diode temperature range in which the laser output may be enabled
+    pub temperature_window_c: Option<Range>,
+    /// Wavelengths the power meter may be set to
+    pub allowed_wavelength_nm: Option<Range>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Range {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Range {
+    pub fn contains(&self, value: f64) -> bool {
+        (self.min..=self.max).contains(&value)
+    }
+}
+
+impl Default for SafetyRules {
+    fn default() -> Self {
+        SafetyRules {
+            max_current_ma: 1500.0,
+            max_slew_ma_per_ms: Some(1.0),
+            max_power_slew_mw_per_ms: Some(0.1),
+            max_optical_power_dbm: None,
+            require_tec: true,
+            temperature_window_c: None,
+            allowed_wavelength_nm: None,
+        }
+    }
+}
+
+/// Names of the rules, as reported in errors and the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SafetyRule {
+    MaxCurrent,
+    MaxOpticalPower,
+    TecRequired,
+    TemperatureWindow,
+    AllowedWavelength,
+}
+
+impl fmt::Display for SafetyRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SafetyRule::MaxCurrent => "max_current",
+            SafetyRule::MaxOpticalPower => "max_optical_power",
+            SafetyRule::TecRequired => "tec_required",
+            SafetyRule::TemperatureWindow => "temperature_window",
+            SafetyRule::AllowedWavelength => "allowed_wavelength",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Single place where the interlock rules are evaluated. One instance is
+/// shared (behind an `Arc`) by the instrument drivers, which consult it
+/// before every command that could harm the diode or the detector.
+#[derive(Default)]
+pub struct SafetySupervisor {
+    rules: RwLock<SafetyRules>,
+}
+
+impl SafetySupervisor {
+    pub fn new(rules: SafetyRules) -> Self {
+        SafetySupervisor {
+            rules: RwLock::new(rules),
+        }
+    }
+
+    pub fn rules(&self) -> SafetyRules {
+        self.rules.read().unwrap().clone()
+    }
+
+    /// Replaces the rule set, e.g. after the lab config was reloaded.
+    pub fn set_rules(&self, rules: SafetyRules) {
+        info!("Safety rules updated: {:?}", rules);
+        *self.rules.write().unwrap() = rules;
+    }
+
+    fn trip(rule: SafetyRule, reason: String) -> LabError {
+        warn!("Safety rule {} tripped: {}", rule, reason);
+        LabError::SafetyInterlock {
+            rule: rule.to_string(),
+            reason,
+        }
+    }
+
+    pub fn check_current(&self, current_amps: f64) -> Result<()> {
+        if !current_amps.is_finite() || current_amps < 0.0 {
+            return Err(LabError::InvalidParameter(format!("Invalid laser current {} A", current_amps)));
+        }
+        let max_ma = self.rules.read().unwrap().max_current_ma;
+        let current_ma = current_amps * 1000.0;
+        if current_ma > max_ma {
+            return Err(Self::trip(
+                SafetyRule::MaxCurrent,
+                format!("Requested current {:.1} mA exceeds the {:.1} mA limit", current_ma, max_ma),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Rules that gate switching the laser output on. `temperature_c` is the
+    /// diode temperature read back, if the controller provides one.
+    pub fn check_output_enable(&self, tec_on: bool, temperature_c: Option<f64>) -> Result<()> {
+        let rules = self.rules.read().unwrap().clone();
+        if rules.require_tec && !tec_on {
+            return Err(Self::trip(SafetyRule::TecRequired, "Cannot enable laser: TEC is OFF".into()));
+        }
+        if let Some(window) = rules.temperature_window_c {
+            match temperature_c {
+                Some(t) if window.contains(t) => {}
+                Some(t) => {
+                    return Err(Self::trip(
+                        SafetyRule::TemperatureWindow,
+                        format!("Temperature {:.2} °C outside {:.2}..{:.2} °C", t, window.min, window.max),
+                    ));
+                }
+                None => {
+                    return Err(Self::trip(
+                        SafetyRule::TemperatureWindow,
+                        "Temperature unknown, cannot verify the temperature window".into(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A TEC setpoint outside the temperature window would keep the laser
+    /// from ever being enabled, so it is refused up front.
+    pub fn check_temperature_setpoint(&self, setpoint_c: f64) -> Result<()> {
+        if let Some(window) = self.rules.read().unwrap().temperature_window_c {
+            if !window.contains(setpoint_c) {
+                return Err(Self::trip(
+                    SafetyRule::TemperatureWindow,
+                    format!("TEC setpoint {:.2} °C outside {:.2}..{:.2} °C", setpoint_c, window.min, window.max),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuses a constant-power setpoint above the optical power limit.
+    pub fn check_power_setpoint(&self, power_watts: f64) -> Result<()> {
+        if !power_watts.is_finite() || power_watts < 0.0 {
+            return Err(LabError::InvalidParameter(format!("Invalid power setpoint {} W", power_watts)));
+        }
+        let Some(max_dbm) = self.rules.read().unwrap().max_optical_power_dbm else {
+            return Ok(());
+        };
+        let power_dbm = 10.0 * (power_watts * 1000.0).log10();
+        if power_dbm > max_dbm {
+            return Err(Self::trip(
+                SafetyRule::MaxOpticalPower,
+                format!("Power setpoint {:.2} dBm exceeds the {:.2} dBm limit", power_dbm, max_dbm),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks a power meter read-back against the optical power limit.
+    /// Out-of-range readings count as violations when they are overrange.
+    pub fn check_optical_power(&self, readings: &[PowerReading]) -> Result<()> {
+        let Some(max_dbm) = self.rules.read().unwrap().max_optical_power_dbm else {
+            return Ok(());
+        };
+        for reading in readings {
+            let level = match reading.dbm() {
+                Some(dbm) if dbm > max_dbm => format!("{:.2} dBm", dbm),
+                None if reading.status == ReadingStatus::Overrange => "overrange".to_string(),
+                _ => continue,
+            };
+            return Err(Self::trip(
+                SafetyRule::MaxOpticalPower,
+                format!(
+                    "Module {} port {} reads {}, limit {:.2} dBm",
+                    reading.module, reading.port, level, max_dbm
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn check_wavelength(&self, wavelength_nm: f64) -> Result<()> {
+        if let Some(range) = self.rules.read().unwrap().allowed_wavelength_nm {
+            if !range.contains(wavelength_nm) {
+                return Err(Self::trip(
+                    SafetyRule::AllowedWavelength,
+                    format!("Wavelength {} nm outside {}..{} nm", wavelength_nm, range.min, range.max),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supervisor() -> SafetySupervisor {
+        SafetySupervisor::new(SafetyRules {
+            max_current_ma: 100.0,
+            max_optical_power_dbm: Some(10.0),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn current_within_limit_passes() {
+        let safety = supervisor();
+        safety.check_current(0.0).unwrap();
+        safety.check_current(0.1).unwrap();
+        assert!(matches!(safety.check_current(0.101), Err(LabError::SafetyInterlock { .. })));
+    }
+
+    #[test]
+    fn negative_or_non_finite_current_is_refused() {
+        let safety = supervisor();
+        for current_amps in [-0.001, f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(
+                matches!(safety.check_current(current_amps), Err(LabError::InvalidParameter(_))),
+                "{} A passed",
+                current_amps
+            );
+        }
+    }
+
+    #[test]
+    fn power_setpoint_within_limit_passes() {
+        let safety = supervisor();
+        safety.check_power_setpoint(0.0).unwrap();
+        safety.check_power_setpoint(0.01).unwrap();
+        assert!(matches!(safety.check_power_setpoint(0.011), Err(LabError::SafetyInterlock { .. })));
+    }
+
+    #[test]
+    fn negative_or_non_finite_power_setpoint_is_refused() {
+        // Also without a power limit, where nothing else would catch them
+        for safety in [supervisor(), SafetySupervisor::default()] {
+            for power_watts in [-0.001, f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+                assert!(
+                    matches!(safety.check_power_setpoint(power_watts), Err(LabError::InvalidParameter(_))),
+                    "{} W passed",
+                    power_watts
+                );
+            }
+        }
+    }
+}
@@ -1,7 +1,207 @@
+export type ReadingStatus = 'ok' | 'overrange' | 'underrange'
+
+// Power meter input read during a sweep
+export interface Channel {
+    module: number
+    port: number
+  }
+
 export interface SweepPoint {
     timestamp: string
+    current_mA: number | null // setpoint, null in power sweeps
+    power_setpoint_mW: number | null // only in power sweeps
+    measured_current_mA: number
+    voltage_V: number
+    power_dBm: number | null // null when the reading is out of range
+    // null in monitor-photodiode-only sweeps
+    status: ReadingStatus | null
+    module: number | null
+    port: number | null
+    monitor_current_mA: number | null // null when not recorded
+    monitor_power_dBm: number | null // needs the profile's responsivity
+  }
+
+// Payload of the 'sweep-state' event
+export interface SweepStateEvent {
+    state: 'paused' | 'resumed'
+    points: number
+    setpoint_mA: number | null
+    setpoint_mW: number | null // power sweeps
+  }
+
+export interface LiPoint {
     current_mA: number
-    power_dBm: string
-    module: number
+    power_mW: number
+  }
+
+// Threshold current by method, null where the curve does not allow it
+export interface ThresholdEstimates {
+    linear_fit_mA: number | null
+    first_derivative_mA: number | null
+    second_derivative_mA: number | null
+  }
+
+// Least-squares line through the lasing region
+export interface LinearFit {
+    slope_W_per_A: number
+    intercept_mW: number
+    r_squared: number
+    rms_residual_mW: number
+    residuals: { current_mA: number, residual_mW: number }[]
+  }
+
+// Point of an L-I curve marked by the kink and rollover screening
+export interface Annotation {
+    kind: 'kink' | 'rollover_onset' | 'peak_power'
+    current_mA: number
+    power_mW: number
+  }
+
+// Pass/fail flags of the screening, limits from the 'experiment.analysis' config
+export interface Screening {
+    passed: boolean
+    kink_passed: boolean
+    rollover_passed: boolean
+    peak_power_passed: boolean
+    rollover_onset_mA: number | null
+    annotations: Annotation[]
+  }
+
+// Analysis of one L-I curve of a sweep
+export interface CurveAnalysis {
+    source: 'power_meter' | 'monitor_photodiode'
+    module: number | null // null for the monitor photodiode
+    port: number | null
+    points: number
+    threshold: ThresholdEstimates
+    slope_efficiency_W_per_A: number | null
+    max_power_mW: number | null
+    current_at_max_power_mA: number | null
+    fit: LinearFit | null
+    screening: Screening | null // null when the curve has no lasing region
+  }
+
+// Returned by 'run_current_sweep' and 'run_power_sweep'
+export interface SweepOutcome {
+    data_path: string
+    analysis_path: string
+    analysis: CurveAnalysis[]
+  }
+
+// Entry returned by 'discover_instruments'
+export interface DiscoveredInstrument {
+    transport: { type: 'visa', resource: string } | { type: 'tcp', address: string } | { type: 'serial', port: string, baud_rate: number }
+    idn: string
+    manufacturer: string
+    model: string
+    serial: string
+    firmware: string
+    driver: 'cld1015' | 'mpm210h' | 'unknown'
+  }
+
+// CLD1015 regulation: constant current or constant power
+export type OperatingMode = 'current' | 'power'
+
+export type PhotodiodePolarity = 'cathode_grounded' | 'anode_grounded'
+
+// Ratings of one laser diode, from the 'profiles' section of lab_config.json
+export interface LaserProfile {
+    max_current_ma: number
+    threshold_ma: number | null
+    wavelength_nm: number
+    tec_setpoint_c: number
+    tec_current_limit_a: number | null
+    temperature_range_c: { min: number, max: number }
+    monitor_responsivity_a_per_w: number | null
+    monitor_polarity: PhotodiodePolarity | null
+    max_power_mw: number | null
+  }
+
+// Part of the config returned by 'get_lab_config' that the UI uses
+export interface LabConfig {
+    profiles: Record<string, LaserProfile>
+    active_profile: string | null
+  }
+
+export type ConnectionState = 'disconnected' | 'connected' | 'lost'
+
+// Payload of the 'connection-state' event
+export interface ConnectionEvent {
+    device: 'CLD1015' | 'MPM210H'
+    state: ConnectionState
+    attempt: number // reconnect attempts since the link was lost
+    message: string | null
+  }
+
+// Payload of the 'temperature-progress' event, sent while a sweep waits for the TEC
+export interface TemperatureProgress {
+    temperature_c: number
+    setpoint_c: number
+    tolerance_c: number
+    stable_ms: number
+    hold_ms: number
+    elapsed_ms: number
+  }
+
+// CLD1015 hardware protection that switched the laser output off
+export type Protection = 'interlock' | 'over_temperature' | 'open_circuit'
+
+// Payload of the 'device-telemetry' event; fields are null when not sampled
+export interface DeviceTelemetry {
+    timestamp: string
+    cld1015: {
+      state: ConnectionState | null // null when busy
+      busy: boolean
+      tec_on: boolean | null
+      laser_on: boolean | null
+      setpoint_mA: number | null
+      temperature_c: number | null
+      thermistor_ohms: number | null
+      tec_setpoint_c: number | null
+      tripped: Protection[]
+      // Error queue not empty, from the status byte; the entries are not read
+      error_pending: boolean | null
+      error: string | null
+    }
+    mpm210h: {
+      state: ConnectionState | null
+      busy: boolean
+      wavelength_nm: number | null
+      unit: 'dBm' | 'mW' | 'W' | null
+      modules: string | null
+      error_pending: boolean | null
+      error: string | null
+    }
+  }
+
+export type LabErrorKind =
+  | 'not_connected'
+  | 'timeout'
+  | 'safety_interlock'
+  | 'protection_tripped'
+  | 'instrument_error'
+  | 'parse_error'
+  | 'invalid_parameter'
+  | 'aborted'
+  | 'busy'
+  | 'io'
+
+// Error payload returned by every Tauri command
+export interface LabError {
+    kind: LabErrorKind
+    message: string
+    device?: string
+    command?: string
+    timeout_ms?: number
+    code?: number
+    rule?: string
+    protection?: Protection
   }
-  
\ No newline at end of file
+
+export function isLabError(e: unknown): e is LabError {
+  return typeof e === 'object' && e !== null && 'kind' in e && 'message' in e
+}
+
+export function describeError(e: unknown): string {
+  return isLabError(e) ? e.message : String(e)
+}